@@ -602,8 +602,13 @@ CREATE FUNCTION public.record_authentication_change() RETURNS trigger
 AS
 $$
 BEGIN
-    IF OLD.uid IS NOT NULL THEN
-        INSERT INTO public.authentication_log(uid, account, credential) VALUES (OLD.uid, OLD.account, OLD.credential);
+    IF TG_OP = 'INSERT' THEN
+        INSERT INTO public.authentication_log(uid, login_type, account, action)
+        VALUES (NEW.uid, NEW.login_type, NEW.account, 'bind');
+    ELSIF OLD.uid IS NOT NULL THEN
+        INSERT INTO public.authentication_log(uid, login_type, account, action)
+        VALUES (OLD.uid, OLD.login_type, OLD.account,
+                CASE TG_OP WHEN 'DELETE' THEN 'unbind' ELSE 'update' END);
     END IF;
     RETURN NEW;
 END;
@@ -612,6 +617,22 @@ $$;
 
 ALTER FUNCTION public.record_authentication_change() OWNER TO postgres;
 
+--
+-- Name: forbid_audit_log_change(); Type: FUNCTION; Schema: public; Owner: postgres
+--
+
+CREATE FUNCTION public.forbid_audit_log_change() RETURNS trigger
+    LANGUAGE plpgsql
+AS
+$$
+BEGIN
+    RAISE EXCEPTION 'audit_log is append-only';
+END;
+$$;
+
+
+ALTER FUNCTION public.forbid_audit_log_change() OWNER TO postgres;
+
 --
 -- Name: search_notice(text); Type: FUNCTION; Schema: search; Owner: postgres
 --
@@ -1095,7 +1116,8 @@ CREATE TABLE public.person
     city        character varying(50),
     language    character varying(30),
     create_time timestamp without time zone DEFAULT now() NOT NULL,
-    is_admin    boolean                     DEFAULT false
+    is_admin    boolean                     DEFAULT false,
+    revoked_before timestamp with time zone
 );
 
 
@@ -1132,7 +1154,7 @@ SELECT 1               AS source,
        events.image
 FROM (events.events
          LEFT JOIN public.person p ON ((events.publisher_uid = p.uid)))
-WHERE ((events.title)::text ~~ '%%'::text)
+WHERE ((events.title)::text ~~ '%%'::text) AND (events.deleted = false)
 ORDER BY 7 DESC;
 
 
@@ -1592,7 +1614,7 @@ CREATE TABLE public.authentication
     uid        integer               NOT NULL,
     login_type integer               NOT NULL,
     account    character varying(40) NOT NULL,
-    credential character varying(40)
+    credential character varying(128)
 );
 
 
@@ -1608,13 +1630,107 @@ CREATE TABLE public.authentication_log
     ts         timestamp without time zone DEFAULT now() NOT NULL,
     uid        integer                                   NOT NULL,
     account    text                                      NOT NULL,
-    credential text
+    login_type integer,
+    action     character varying(10)
 );
 
 
 ALTER TABLE public.authentication_log
     OWNER TO postgres;
 
+CREATE INDEX authentication_log_uid_ts_index ON public.authentication_log USING btree (uid, ts);
+
+--
+-- Name: role_permission; Type: TABLE; Schema: public; Owner: postgres
+--
+
+CREATE TABLE public.role_permission
+(
+    role       character varying(40) NOT NULL,
+    permission character varying(40) NOT NULL,
+    PRIMARY KEY (role, permission)
+);
+
+
+ALTER TABLE public.role_permission
+    OWNER TO postgres;
+
+--
+-- Name: person_role; Type: TABLE; Schema: public; Owner: postgres
+--
+
+CREATE TABLE public.person_role
+(
+    uid  integer               NOT NULL,
+    role character varying(40) NOT NULL,
+    PRIMARY KEY (uid, role)
+);
+
+
+ALTER TABLE public.person_role
+    OWNER TO postgres;
+
+--
+-- Name: session; Type: TABLE; Schema: public; Owner: postgres
+--
+
+CREATE TABLE public.session
+(
+    jti        uuid                                   NOT NULL PRIMARY KEY,
+    uid        integer                                NOT NULL,
+    created_at timestamp with time zone DEFAULT now() NOT NULL,
+    revoked_at timestamp with time zone
+);
+
+
+ALTER TABLE public.session
+    OWNER TO postgres;
+
+CREATE INDEX session_uid_index ON public.session USING btree (uid);
+
+--
+-- Name: refresh_token; Type: TABLE; Schema: public; Owner: postgres
+--
+
+CREATE TABLE public.refresh_token
+(
+    token      bytea                                  NOT NULL PRIMARY KEY,
+    family     uuid                                   NOT NULL,
+    uid        integer                                NOT NULL,
+    issued_at  timestamp with time zone DEFAULT now() NOT NULL,
+    expires_at timestamp with time zone               NOT NULL,
+    used_at    timestamp with time zone,
+    revoked    boolean                  DEFAULT false NOT NULL
+);
+
+
+ALTER TABLE public.refresh_token
+    OWNER TO postgres;
+
+CREATE INDEX refresh_token_family_index ON public.refresh_token USING btree (family);
+
+--
+-- Name: audit_log; Type: TABLE; Schema: public; Owner: postgres
+--
+
+CREATE TABLE public.audit_log
+(
+    id         bigserial                                 NOT NULL PRIMARY KEY,
+    ts         timestamp without time zone DEFAULT now() NOT NULL,
+    actor      integer                                   NOT NULL,
+    action     character varying(40)                     NOT NULL,
+    target     text                                      NOT NULL,
+    request_id character varying(64),
+    diff       jsonb
+);
+
+
+ALTER TABLE public.audit_log
+    OWNER TO postgres;
+
+CREATE INDEX audit_log_actor_index ON public.audit_log USING btree (actor);
+CREATE INDEX audit_log_target_index ON public.audit_log USING btree (target);
+
 --
 -- Name: pages; Type: TABLE; Schema: search; Owner: postgres
 --
@@ -1666,7 +1782,7 @@ CREATE TABLE public.identities
 (
     uid             integer               NOT NULL,
     student_id      character(10)         NOT NULL,
-    oa_secret       character varying(32),
+    oa_secret       text,
     oa_certified    boolean DEFAULT false NOT NULL,
     identity_number character varying(18),
     real_name       character varying(40) NOT NULL
@@ -2289,12 +2405,34 @@ EXECUTE FUNCTION freshman.record_contact_change();
 --
 
 CREATE TRIGGER on_change_authentication
-    AFTER DELETE OR UPDATE
+    AFTER INSERT OR DELETE OR UPDATE
     ON public.authentication
     FOR EACH ROW
 EXECUTE FUNCTION public.record_authentication_change();
 
 
+--
+-- Name: audit_log on_change_audit_log; Type: TRIGGER; Schema: public; Owner: postgres
+--
+
+CREATE TRIGGER on_change_audit_log
+    BEFORE DELETE OR UPDATE
+    ON public.audit_log
+    FOR EACH ROW
+EXECUTE FUNCTION public.forbid_audit_log_change();
+
+
+--
+-- Name: audit_log on_truncate_audit_log; Type: TRIGGER; Schema: public; Owner: postgres
+--
+
+CREATE TRIGGER on_truncate_audit_log
+    BEFORE TRUNCATE
+    ON public.audit_log
+    FOR EACH STATEMENT
+EXECUTE FUNCTION public.forbid_audit_log_change();
+
+
 --
 -- Name: students approvals_person_uid_fk; Type: FK CONSTRAINT; Schema: checking; Owner: postgres
 --
@@ -2383,6 +2521,48 @@ ALTER TABLE ONLY public.authentication
     ADD CONSTRAINT verifications_persons_uid_fk FOREIGN KEY (uid) REFERENCES public.person (uid) ON UPDATE RESTRICT ON DELETE CASCADE;
 
 
+--
+-- Name: refresh_token refresh_token_person_uid_fk; Type: FK CONSTRAINT; Schema: public; Owner: postgres
+--
+
+ALTER TABLE ONLY public.refresh_token
+    ADD CONSTRAINT refresh_token_person_uid_fk FOREIGN KEY (uid) REFERENCES public.person (uid) ON UPDATE RESTRICT ON DELETE CASCADE;
+
+
+--
+-- Name: session session_person_uid_fk; Type: FK CONSTRAINT; Schema: public; Owner: postgres
+--
+
+ALTER TABLE ONLY public.session
+    ADD CONSTRAINT session_person_uid_fk FOREIGN KEY (uid) REFERENCES public.person (uid) ON UPDATE RESTRICT ON DELETE CASCADE;
+
+
+--
+-- Name: person_role person_role_person_uid_fk; Type: FK CONSTRAINT; Schema: public; Owner: postgres
+--
+
+ALTER TABLE ONLY public.person_role
+    ADD CONSTRAINT person_role_person_uid_fk FOREIGN KEY (uid) REFERENCES public.person (uid) ON UPDATE RESTRICT ON DELETE CASCADE;
+
+
+--
+-- Data for Name: role_permission; Type: TABLE DATA; Schema: public; Owner: postgres
+--
+
+INSERT INTO public.role_permission (role, permission)
+VALUES ('admin', 'user.manage'),
+       ('admin', 'agent.manage'),
+       ('admin', 'attachment.manage'),
+       ('admin', 'mall.moderate'),
+       ('admin', 'audit.view'),
+       ('admin', 'event.manage'),
+       ('admin', 'freshman.manage'),
+       ('admin', 'role.manage'),
+       ('mall-moderator', 'mall.moderate'),
+       ('event-organizer', 'event.manage'),
+       ('freshman-operator', 'freshman.manage');
+
+
 --
 -- PostgreSQL database dump complete
 --
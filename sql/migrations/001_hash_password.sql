@@ -0,0 +1,5 @@
+-- Password credentials are stored as argon2id hashes, which are longer than the old plaintext column allows.
+-- Existing plaintext passwords are rehashed by the server on the next successful login.
+
+ALTER TABLE public.authentication
+    ALTER COLUMN credential TYPE character varying(128);
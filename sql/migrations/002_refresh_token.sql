@@ -0,0 +1,20 @@
+-- Refresh tokens. A token is stored as its SHA-256 digest, and tokens rotated from the same login share a family.
+
+CREATE TABLE public.refresh_token
+(
+    token      bytea                                  NOT NULL PRIMARY KEY,
+    family     uuid                                   NOT NULL,
+    uid        integer                                NOT NULL
+        REFERENCES public.person (uid) ON UPDATE RESTRICT ON DELETE CASCADE,
+    issued_at  timestamp with time zone DEFAULT now() NOT NULL,
+    expires_at timestamp with time zone               NOT NULL,
+    used_at    timestamp with time zone,
+    revoked    boolean                  DEFAULT false NOT NULL
+);
+
+
+ALTER TABLE public.refresh_token
+    OWNER TO postgres;
+
+CREATE INDEX refresh_token_family_index ON public.refresh_token USING btree (family);
+
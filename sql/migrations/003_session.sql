@@ -0,0 +1,17 @@
+-- Login sessions. Access tokens carry the session ID in the jti claim, and refresh tokens of a login
+-- use it as their family.
+
+CREATE TABLE public.session
+(
+    jti        uuid                                   NOT NULL PRIMARY KEY,
+    uid        integer                                NOT NULL
+        REFERENCES public.person (uid) ON UPDATE RESTRICT ON DELETE CASCADE,
+    created_at timestamp with time zone DEFAULT now() NOT NULL,
+    revoked_at timestamp with time zone
+);
+
+
+ALTER TABLE public.session
+    OWNER TO postgres;
+
+CREATE INDEX session_uid_index ON public.session USING btree (uid);
@@ -0,0 +1,40 @@
+-- Roles of users and permissions granted by roles. Users with is_admin still have all permissions.
+
+CREATE TABLE public.role_permission
+(
+    role       character varying(40) NOT NULL,
+    permission character varying(40) NOT NULL,
+    PRIMARY KEY (role, permission)
+);
+
+
+ALTER TABLE public.role_permission
+    OWNER TO postgres;
+
+--
+-- Name: person_role; Type: TABLE; Schema: public; Owner: postgres
+--
+
+CREATE TABLE public.person_role
+(
+    uid  integer               NOT NULL
+        REFERENCES public.person (uid) ON UPDATE RESTRICT ON DELETE CASCADE,
+    role character varying(40) NOT NULL,
+    PRIMARY KEY (uid, role)
+);
+
+
+ALTER TABLE public.person_role
+    OWNER TO postgres;
+
+INSERT INTO public.role_permission (role, permission)
+VALUES ('admin', 'user.manage'),
+       ('admin', 'agent.manage'),
+       ('admin', 'attachment.manage'),
+       ('admin', 'mall.moderate'),
+       ('mall-moderator', 'mall.moderate');
+
+INSERT INTO public.person_role (uid, role)
+SELECT uid, 'admin'
+FROM public.person
+WHERE is_admin;
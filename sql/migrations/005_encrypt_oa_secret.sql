@@ -0,0 +1,5 @@
+-- OA secrets are stored encrypted, like "v1:<key id>:<wrapped data key>:<ciphertext>", which is longer than the
+-- old plaintext column allows. Run `kite-server encrypt-secrets` after the migration to encrypt existing rows.
+
+ALTER TABLE public.identity
+    ALTER COLUMN oa_secret TYPE text;
@@ -0,0 +1,44 @@
+-- Record the login type and kind of each change in authentication_log, and record new bindings too, so that
+-- users can see the history of their login methods. Rows logged before have no login type or action.
+-- The log used to keep the old credential of each change, which was the plaintext password of accounts not
+-- rehashed yet, so it no longer records credentials and those logged before are purged.
+-- Dropped column data stays on disk until the table is rewritten, so run `VACUUM FULL public.authentication_log;`
+-- after this migration.
+
+ALTER TABLE public.authentication_log
+    ADD COLUMN login_type integer,
+    ADD COLUMN action     character varying(10);
+
+CREATE INDEX authentication_log_uid_ts_index ON public.authentication_log USING btree (uid, ts);
+
+CREATE OR REPLACE FUNCTION public.record_authentication_change() RETURNS trigger
+    LANGUAGE plpgsql
+AS
+$$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        INSERT INTO public.authentication_log(uid, login_type, account, action)
+        VALUES (NEW.uid, NEW.login_type, NEW.account, 'bind');
+    ELSIF OLD.uid IS NOT NULL THEN
+        INSERT INTO public.authentication_log(uid, login_type, account, action)
+        VALUES (OLD.uid, OLD.login_type, OLD.account,
+                CASE TG_OP WHEN 'DELETE' THEN 'unbind' ELSE 'update' END);
+    END IF;
+    RETURN NEW;
+END;
+$$;
+
+DROP TRIGGER on_change_authentication ON public.authentication;
+
+CREATE TRIGGER on_change_authentication
+    AFTER INSERT OR DELETE OR UPDATE
+    ON public.authentication
+    FOR EACH ROW
+EXECUTE FUNCTION public.record_authentication_change();
+
+UPDATE public.authentication_log
+SET credential = NULL
+WHERE credential IS NOT NULL;
+
+ALTER TABLE public.authentication_log
+    DROP COLUMN credential;
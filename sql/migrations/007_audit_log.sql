@@ -0,0 +1,46 @@
+-- Append-only log of privileged actions, like administrators editing other users.
+
+CREATE TABLE public.audit_log
+(
+    id         bigserial                                 NOT NULL PRIMARY KEY,
+    ts         timestamp without time zone DEFAULT now() NOT NULL,
+    actor      integer                                   NOT NULL,
+    action     character varying(40)                     NOT NULL,
+    target     text                                      NOT NULL,
+    request_id character varying(64),
+    diff       jsonb
+);
+
+
+ALTER TABLE public.audit_log
+    OWNER TO postgres;
+
+CREATE INDEX audit_log_actor_index ON public.audit_log USING btree (actor);
+CREATE INDEX audit_log_target_index ON public.audit_log USING btree (target);
+
+CREATE FUNCTION public.forbid_audit_log_change() RETURNS trigger
+    LANGUAGE plpgsql
+AS
+$$
+BEGIN
+    RAISE EXCEPTION 'audit_log is append-only';
+END;
+$$;
+
+
+ALTER FUNCTION public.forbid_audit_log_change() OWNER TO postgres;
+
+CREATE TRIGGER on_change_audit_log
+    BEFORE DELETE OR UPDATE
+    ON public.audit_log
+    FOR EACH ROW
+EXECUTE FUNCTION public.forbid_audit_log_change();
+
+CREATE TRIGGER on_truncate_audit_log
+    BEFORE TRUNCATE
+    ON public.audit_log
+    FOR EACH STATEMENT
+EXECUTE FUNCTION public.forbid_audit_log_change();
+
+INSERT INTO public.role_permission (role, permission)
+VALUES ('admin', 'audit.view');
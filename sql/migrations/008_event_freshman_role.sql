@@ -0,0 +1,9 @@
+-- Roles for club staff publishing events and for volunteers helping freshmen at the registration desk, and the
+-- permission to give roles to users.
+
+INSERT INTO public.role_permission (role, permission)
+VALUES ('admin', 'event.manage'),
+       ('admin', 'freshman.manage'),
+       ('admin', 'role.manage'),
+       ('event-organizer', 'event.manage'),
+       ('freshman-operator', 'freshman.manage');
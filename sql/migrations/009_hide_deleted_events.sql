@@ -0,0 +1,30 @@
+-- Events can be deleted by their publishers and event managers now, so stop listing those marked deleted.
+
+CREATE OR REPLACE VIEW events.all_events AS
+SELECT 0                     AS source,
+       sc_events.activity_id AS id,
+       NULL::integer         AS publisher_uid,
+       sc_events.manager     AS publisher_name,
+       sc_events.title,
+       sc_events.tags,
+       sc_events.start_time,
+       sc_events.end_time,
+       sc_events.place,
+       sc_events.image
+FROM events.sc_events
+WHERE (sc_events.hide = false)
+UNION ALL
+SELECT 1               AS source,
+       events.event_id AS id,
+       events.publisher_uid,
+       p.nick_name     AS publisher_name,
+       events.title,
+       events.tags,
+       events.start_time,
+       events.end_time,
+       events.place,
+       events.image
+FROM (events.events
+         LEFT JOIN public.person p ON ((events.publisher_uid = p.uid)))
+WHERE ((events.title)::text ~~ '%%'::text) AND (events.deleted = false)
+ORDER BY 7 DESC;
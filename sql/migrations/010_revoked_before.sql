@@ -0,0 +1,5 @@
+-- Tokens issued before sessions were introduced carry no jti, so they can not be revoked with their sessions.
+-- Revoking all sessions of a user records the time, and those tokens issued before it are rejected.
+
+ALTER TABLE public.person
+    ADD COLUMN revoked_before timestamp with time zone;
@@ -3,14 +3,24 @@ use chrono::{DateTime, Local};
 pub use host::AgentManager;
 pub use model::*;
 pub use protocol::{ErrorResponse, RequestFrame, RequestPayload, ResponsePayload, ResponseResult};
+pub use strategy::StrategyKind;
 
+mod cache;
+#[cfg(test)]
+pub(crate) mod fake;
+mod handshake;
 mod host;
 mod model;
 mod protocol;
+mod record;
+mod session;
+mod strategy;
+mod tls;
+pub mod websocket;
 
 pub type Result<T> = anyhow::Result<T>;
 
-#[derive(Debug, ToPrimitive, thiserror::Error)]
+#[derive(Debug, Clone, Copy, ToPrimitive, thiserror::Error)]
 #[error("代理节点错误: {}")]
 /// Business error of web socket host
 pub enum HostError {
@@ -22,6 +32,15 @@ pub enum HostError {
     Disconnected = 122,
     #[error("Agent 端响应不匹配")]
     Mismatched = 123,
+    #[error("Agent 节点认证失败")]
+    AuthFailed = 124,
+    #[error("代理节点繁忙, 请稍后重试")]
+    Overloaded = 125,
+    #[error("没有支持该请求的代理节点, 请等待更新")]
+    Unsupported = 126,
+    /// Reported by agents when a session handle is unknown or expired. The host logs in again in this case.
+    #[error("代理节点会话已过期")]
+    SessionExpired = 127,
 }
 
 /// Agent state
@@ -35,8 +54,18 @@ pub struct AgentStatus {
     pub intranet_addr: String,
     /// External network address
     pub external_addr: String,
+    /// Protocol version
+    pub version: u16,
+    /// Supported request kinds
+    pub capabilities: Vec<String>,
     /// Processed requests' count
     pub requests: u32,
+    /// Requests in flight
+    pub in_flight: u32,
+    /// Background requests in flight
+    pub background: u32,
+    /// Response latency EWMA in microseconds
+    pub latency: u32,
     /// Last use.
     pub last_use: DateTime<Local>,
 }
@@ -0,0 +1,177 @@
+//! Response cache for agent requests, so that slowly changing data need not be fetched on each query.
+//!
+//! Items are indexed by the HMAC-SHA256 of the serialized request, with a random key generated on start.
+//! Requests carrying credentials only hit entries created with the same account and password, and no
+//! plaintext credential is kept in memory. Since the key is secret, a dump of the cache can not be used to
+//! guess passwords offline either.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use hmac::{Hmac, Mac, NewMac};
+use rand::Rng;
+use sha2::Sha256;
+use tokio::sync::RwLock;
+
+use super::protocol::{RequestFrame, ResponsePayload};
+
+/// Max cached items, expired items are cleaned when it is reached.
+const MAX_CACHE_ITEMS: usize = 10000;
+
+struct CacheItem {
+    expire: Instant,
+    payload: ResponsePayload,
+}
+
+pub struct ResponseCache {
+    /// TTL by request kind. Kinds not listed are not cached.
+    ttl: HashMap<String, Duration>,
+    /// HMAC key of item keys.
+    secret: [u8; 32],
+    items: RwLock<HashMap<Vec<u8>, CacheItem>>,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: &HashMap<String, u64>) -> Self {
+        Self {
+            ttl: ttl
+                .iter()
+                .map(|(kind, &secs)| (kind.clone(), Duration::from_secs(secs)))
+                .collect(),
+            secret: rand::thread_rng().gen(),
+            items: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn key_of(&self, request: &RequestFrame) -> Option<Vec<u8>> {
+        let bytes = bincode::serialize(request.payload()).ok()?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).ok()?;
+
+        mac.update(&bytes);
+        Some(mac.finalize().into_bytes().to_vec())
+    }
+
+    fn ttl_of(&self, request: &RequestFrame) -> Option<Duration> {
+        if !request.is_cacheable() {
+            return None;
+        }
+        self.ttl.get(request.kind()).copied()
+    }
+
+    pub async fn get(&self, request: &RequestFrame) -> Option<ResponsePayload> {
+        self.ttl_of(request)?;
+        let key = self.key_of(request)?;
+
+        let items = self.items.read().await;
+        items
+            .get(&key)
+            .filter(|item| item.expire > Instant::now())
+            .map(|item| item.payload.clone())
+    }
+
+    pub async fn put(&self, request: &RequestFrame, payload: &ResponsePayload) {
+        let ttl = match self.ttl_of(request) {
+            Some(ttl) => ttl,
+            None => return,
+        };
+        let key = match self.key_of(request) {
+            Some(key) => key,
+            None => return,
+        };
+
+        let now = Instant::now();
+        let mut items = self.items.write().await;
+        if items.len() >= MAX_CACHE_ITEMS {
+            items.retain(|_, item| item.expire > now);
+            if items.len() >= MAX_CACHE_ITEMS {
+                return;
+            }
+        }
+        items.insert(
+            key,
+            CacheItem {
+                expire: now + ttl,
+                payload: payload.clone(),
+            },
+        );
+    }
+
+    /// Remove all items, return the count removed.
+    pub async fn purge(&self) -> usize {
+        let mut items = self.items.write().await;
+        let count = items.len();
+
+        items.clear();
+        count
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bridge::{
+        BookHoldingRequest, HoldingPreviews, RequestPayload, SchoolYear, ScoreRequest, Semester,
+    };
+
+    fn cache(kind: &str, secs: u64) -> ResponseCache {
+        let mut ttl = HashMap::new();
+        ttl.insert(kind.to_string(), secs);
+        ResponseCache::new(&ttl)
+    }
+
+    fn book_holding() -> RequestFrame {
+        RequestFrame::new(RequestPayload::BookHoldingInfo(BookHoldingRequest {
+            book_id_list: vec!["1".to_string()],
+        }))
+    }
+
+    fn holding() -> ResponsePayload {
+        ResponsePayload::BookHoldingInfo(HoldingPreviews {
+            holding_previews: HashMap::new(),
+        })
+    }
+
+    fn score(account: &str, password: &str) -> RequestFrame {
+        RequestFrame::new(RequestPayload::Score(ScoreRequest {
+            account: account.to_string(),
+            passwd: password.to_string(),
+            school_year: SchoolYear::AllYear,
+            semester: Semester::All,
+        }))
+    }
+
+    #[tokio::test]
+    pub async fn expire_after_ttl() {
+        let cache = cache("BookHoldingInfo", 1);
+        cache.put(&book_holding(), &holding()).await;
+        assert!(cache.get(&book_holding()).await.is_some());
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(cache.get(&book_holding()).await.is_none());
+
+        // Kinds without a TTL are not cached.
+        let cache = ResponseCache::new(&HashMap::new());
+        cache.put(&book_holding(), &holding()).await;
+        assert!(cache.get(&book_holding()).await.is_none());
+    }
+
+    #[tokio::test]
+    pub async fn separate_credentials() {
+        let cache = cache("Score", 60);
+        cache.put(&score("1810000000", "secret"), &holding()).await;
+
+        assert!(cache.get(&score("1810000000", "secret")).await.is_some());
+        assert!(cache.get(&score("1810000000", "guess")).await.is_none());
+        assert!(cache.get(&score("1810000001", "secret")).await.is_none());
+        assert!(cache.get(&score("1810000001", "")).await.is_none());
+    }
+
+    #[tokio::test]
+    pub async fn keyed_digest() {
+        let (a, b) = (cache("Score", 60), cache("Score", 60));
+        let request = score("1810000000", "secret");
+
+        assert_ne!(a.key_of(&request), request.digest());
+        assert_ne!(a.key_of(&request), b.key_of(&request));
+    }
+}
@@ -0,0 +1,539 @@
+//! Fake agent answering from scripted fixtures, for testing the bridge without the campus network.
+//!
+//! It connects to the host port and speaks the same protocol as a real agent: the handshake first, and
+//! then `Tagged<RequestFrame>` / `Tagged<ResponseFrame>` frames. Ping and AgentInfo are answered by
+//! itself, and other kinds by the fixtures registered with `FakeAgent::on`. PortalAuth is answered with
+//! a session handle unless a fixture is registered for it.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_bincode::{AsyncBincodeStream, AsyncDestination};
+use futures::{SinkExt, StreamExt};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use crate::config::HostConfig;
+
+use super::handshake::{Challenge, ChallengeResponse};
+use super::host::AgentManager;
+use super::protocol::{
+    ErrorResponse, RequestFrame, RequestPayload, ResponseFrame, ResponsePayload, ResponseResult, Tagged,
+};
+use super::{AgentInfo, HostError, PortalAuthResponse};
+
+/// Pre-shared key used by the test host.
+pub const SECRET: &str = "fake-agent-secret";
+
+type Fixture = Box<dyn Fn(&RequestPayload) -> ResponseResult + Send + Sync>;
+
+type HandshakeStream<'a> =
+    AsyncBincodeStream<&'a mut TcpStream, Challenge, ChallengeResponse, AsyncDestination>;
+type AgentStream =
+    AsyncBincodeStream<TcpStream, Tagged<RequestFrame>, Tagged<ResponseFrame>, AsyncDestination>;
+
+pub struct FakeAgent {
+    name: String,
+    secret: String,
+    /// Fixtures by request kind. The agent advertises these kinds as its capabilities.
+    fixtures: HashMap<&'static str, Fixture>,
+    /// Delay before each response except Ping and AgentInfo, to simulate a slow campus system.
+    delay: Duration,
+    /// Close the connection on the first request other than Ping and AgentInfo, to simulate a crashed agent.
+    hang_up: bool,
+}
+
+impl FakeAgent {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            secret: SECRET.to_string(),
+            fixtures: HashMap::new(),
+            delay: Duration::default(),
+            hang_up: false,
+        }
+    }
+
+    pub fn secret(mut self, secret: &str) -> Self {
+        self.secret = secret.to_string();
+        self
+    }
+
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn hang_up(mut self) -> Self {
+        self.hang_up = true;
+        self
+    }
+
+    /// Answer requests of the kind, like "BookHoldingInfo", with the fixture.
+    pub fn on<F>(mut self, kind: &'static str, fixture: F) -> Self
+    where
+        F: Fn(&RequestPayload) -> ResponseResult + Send + Sync + 'static,
+    {
+        self.fixtures.insert(kind, Box::new(fixture));
+        self
+    }
+
+    fn info(&self) -> AgentInfo {
+        AgentInfo {
+            name: self.name.clone(),
+            intranet_addr: "127.0.0.1".to_string(),
+            protocol_version: 3,
+            capabilities: self.fixtures.keys().map(|kind| kind.to_string()).collect(),
+        }
+    }
+
+    fn answer(&self, payload: &RequestPayload) -> ResponseResult {
+        match payload {
+            RequestPayload::Ping(message) => Ok(ResponsePayload::Pong(message.clone())),
+            RequestPayload::AgentInfo(_) => Ok(ResponsePayload::Credential(self.info())),
+            RequestPayload::PortalAuth(request) if !self.fixtures.contains_key("PortalAuth") => {
+                Ok(ResponsePayload::PortalAuth(PortalAuthResponse::Session(format!(
+                    "session-{}",
+                    request.account
+                ))))
+            }
+            _ => match self.fixtures.get(payload.kind()) {
+                Some(fixture) => fixture(payload),
+                None => Err(ErrorResponse {
+                    code: 1,
+                    msg: format!("No fixture for {}", payload.kind()),
+                }),
+            },
+        }
+    }
+
+    async fn handshake(&self, stream: &mut TcpStream) -> Result<()> {
+        let mut framed: HandshakeStream = AsyncBincodeStream::from(stream).for_async();
+
+        let challenge = framed.next().await.ok_or(HostError::Disconnected)??;
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC can take key of any size");
+        mac.update(&challenge.nonce);
+        framed
+            .send(ChallengeResponse {
+                name: self.name.clone(),
+                digest: mac.finalize().into_bytes().to_vec(),
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Answer requests one by one until the connection is closed.
+    async fn serve(self, stream: TcpStream) {
+        let mut framed: AgentStream = AsyncBincodeStream::from(stream).for_async();
+
+        while let Some(Ok(request)) = framed.next().await {
+            // Ping and AgentInfo are answered at once, so that slow agents still join and stay in the pool.
+            if !matches!(request.v.payload().kind(), "Ping" | "AgentInfo") {
+                if self.hang_up {
+                    break;
+                }
+                tokio::time::sleep(self.delay).await;
+            }
+            let frame = ResponseFrame {
+                payload: self.answer(request.v.payload()),
+                request_id: request.v.request_id().to_string(),
+            };
+            let response = Tagged {
+                v: frame,
+                tag: request.tag,
+            };
+            if framed.send(response).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Connect to the host and run the handshake, then answer requests in background.
+    pub async fn connect(self, addr: &str) -> Result<JoinHandle<()>> {
+        let mut stream = TcpStream::connect(addr).await?;
+
+        self.handshake(&mut stream).await?;
+        Ok(tokio::spawn(self.serve(stream)))
+    }
+}
+
+/// Start a host on a random local port, with extra lines of host config. Return the manager and its address.
+pub async fn start_host(extra_config: &str) -> (AgentManager, String) {
+    let config_text = format!(
+        "bind = \"127.0.0.1:0\"\nmax = 8\nsecret = \"{}\"\n{}",
+        SECRET, extra_config
+    );
+    let config: HostConfig = toml::from_str(&config_text).unwrap();
+    let manager = AgentManager::new(&config);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = manager.clone();
+    tokio::spawn(async move { server.serve(listener).await });
+
+    (manager, addr)
+}
+
+/// Wait until there are `count` agents in the pool, or return false after a second.
+pub async fn wait_agents(manager: &AgentManager, count: usize) -> bool {
+    for _ in 0..100 {
+        if manager.get_client_list().await.len() >= count {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::bridge::{BookHoldingRequest, HoldingPreviews, SchoolYear, ScoreRequest, Semester};
+
+    fn book_holding(id: &str) -> RequestFrame {
+        RequestFrame::new(RequestPayload::BookHoldingInfo(BookHoldingRequest {
+            book_id_list: vec![id.to_string()],
+        }))
+    }
+
+    fn empty_holding(_: &RequestPayload) -> ResponseResult {
+        Ok(ResponsePayload::BookHoldingInfo(HoldingPreviews {
+            holding_previews: HashMap::new(),
+        }))
+    }
+
+    #[tokio::test]
+    pub async fn request_fixture() {
+        let (manager, addr) = start_host("").await;
+        FakeAgent::new("agent-1")
+            .on("BookHoldingInfo", empty_holding)
+            .on("Score", |_| {
+                Err(ErrorResponse {
+                    code: 10,
+                    msg: "error".to_string(),
+                })
+            })
+            .connect(&addr)
+            .await
+            .unwrap();
+        assert!(wait_agents(&manager, 1).await);
+
+        let ping = RequestFrame::new(RequestPayload::Ping("hello".to_string()));
+        let response = manager.request(ping).await.unwrap();
+        assert!(matches!(response, Ok(ResponsePayload::Pong(s)) if s == "hello"));
+
+        let response = manager.request(book_holding("1")).await.unwrap();
+        assert!(matches!(response, Ok(ResponsePayload::BookHoldingInfo(_))));
+
+        // Errors reported by the agent are passed to the caller.
+        let score = RequestFrame::new(RequestPayload::Score(ScoreRequest {
+            account: String::new(),
+            passwd: String::new(),
+            school_year: SchoolYear::AllYear,
+            semester: Semester::All,
+        }));
+        let response = manager.request(score).await.unwrap();
+        assert!(matches!(response, Err(ErrorResponse { code: 10, .. })));
+    }
+
+    fn score(password: &str) -> RequestFrame {
+        RequestFrame::new(RequestPayload::Score(ScoreRequest {
+            account: "1810000000".to_string(),
+            passwd: password.to_string(),
+            school_year: SchoolYear::AllYear,
+            semester: Semester::All,
+        }))
+    }
+
+    #[tokio::test]
+    pub async fn login_once_per_session() {
+        let (manager, addr) = start_host("").await;
+        let logins = Arc::new(AtomicUsize::new(0));
+        let counter = logins.clone();
+        let expired = Arc::new(AtomicUsize::new(0));
+        let flag = expired.clone();
+        FakeAgent::new("agent-1")
+            .on("PortalAuth", move |_| {
+                let n = counter.fetch_add(1, Ordering::SeqCst);
+                Ok(ResponsePayload::PortalAuth(PortalAuthResponse::Session(format!(
+                    "session-{}",
+                    n
+                ))))
+            })
+            .on("Score", move |payload| match payload {
+                // The password is replaced by the session handle.
+                RequestPayload::Score(request) if request.passwd.is_empty() => {
+                    if flag.fetch_add(1, Ordering::SeqCst) == 1 {
+                        Err(ErrorResponse {
+                            code: HostError::SessionExpired as u16,
+                            msg: "expired".to_string(),
+                        })
+                    } else {
+                        Err(ErrorResponse {
+                            code: 10,
+                            msg: "ok".to_string(),
+                        })
+                    }
+                }
+                _ => Err(ErrorResponse {
+                    code: 11,
+                    msg: "password sent".to_string(),
+                }),
+            })
+            .connect(&addr)
+            .await
+            .unwrap();
+        assert!(wait_agents(&manager, 1).await);
+
+        let response = manager.request(score("secret")).await.unwrap();
+        assert!(matches!(response, Err(ErrorResponse { code: 10, .. })));
+        assert_eq!(logins.load(Ordering::SeqCst), 1);
+
+        // The second request finds the session expired, and the host logs in again and resends it.
+        let response = manager.request(score("secret")).await.unwrap();
+        assert!(matches!(response, Err(ErrorResponse { code: 10, .. })));
+        assert_eq!(logins.load(Ordering::SeqCst), 2);
+
+        let response = manager.request(score("secret")).await.unwrap();
+        assert!(matches!(response, Err(ErrorResponse { code: 10, .. })));
+        assert_eq!(logins.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    pub async fn unsupported_request() {
+        let (manager, addr) = start_host("").await;
+        FakeAgent::new("agent-1").connect(&addr).await.unwrap();
+        assert!(wait_agents(&manager, 1).await);
+
+        let error = manager.request(book_holding("1")).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<HostError>(),
+            Some(HostError::Unsupported)
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn reject_wrong_secret() {
+        let (manager, addr) = start_host("").await;
+        FakeAgent::new("agent-1")
+            .secret("wrong")
+            .connect(&addr)
+            .await
+            .unwrap();
+
+        assert!(!wait_agents(&manager, 1).await);
+    }
+
+    #[tokio::test]
+    pub async fn coalesce_shared_requests() {
+        let (manager, addr) = start_host("").await;
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        FakeAgent::new("agent-1")
+            .delay(Duration::from_millis(100))
+            .on("BookHoldingInfo", move |payload| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                empty_holding(payload)
+            })
+            .connect(&addr)
+            .await
+            .unwrap();
+        assert!(wait_agents(&manager, 1).await);
+
+        let (r1, r2) = tokio::join!(
+            manager.request(book_holding("1")),
+            manager.request(book_holding("1"))
+        );
+        assert!(r1.unwrap().is_ok() && r2.unwrap().is_ok());
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    pub async fn cancelled_shared_request() {
+        let (manager, addr) = start_host("").await;
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        FakeAgent::new("agent-1")
+            .delay(Duration::from_millis(100))
+            .on("BookHoldingInfo", move |payload| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                empty_holding(payload)
+            })
+            .connect(&addr)
+            .await
+            .unwrap();
+        assert!(wait_agents(&manager, 1).await);
+
+        // The only waiter gives up, but the request still completes and releases the agent.
+        let cancelled =
+            tokio::time::timeout(Duration::from_millis(20), manager.request(book_holding("1"))).await;
+        assert!(cancelled.is_err());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert_eq!(manager.get_client_list().await[0].in_flight, 0);
+    }
+
+    #[tokio::test]
+    pub async fn background_yields_to_interactive() {
+        let (manager, addr) = start_host("max_in_flight = 3\nreserved_in_flight = 1").await;
+        FakeAgent::new("agent-1")
+            .delay(Duration::from_millis(400))
+            .on("BookHoldingInfo", empty_holding)
+            .connect(&addr)
+            .await
+            .unwrap();
+        assert!(wait_agents(&manager, 1).await);
+
+        let spawn_request = |manager: AgentManager, id: &'static str| {
+            tokio::spawn(async move { manager.request(book_holding(id)).await })
+        };
+
+        // Background requests run beside interactive ones, but leave the reserved slot.
+        let interactive1 = spawn_request(manager.clone(), "1");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let background1 = spawn_request(manager.background(), "2");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let background2 = spawn_request(manager.background(), "3");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let status = &manager.get_client_list().await[0];
+        assert_eq!((status.in_flight, status.background), (2, 1));
+
+        // An interactive request takes the reserved slot, rather than waiting for the identical background one.
+        let interactive2 = spawn_request(manager.clone(), "2");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let status = &manager.get_client_list().await[0];
+        assert_eq!((status.in_flight, status.background), (3, 1));
+
+        for request in [interactive1, background1, background2, interactive2] {
+            assert!(request.await.unwrap().unwrap().is_ok());
+        }
+    }
+
+    fn answer_name(name: &'static str) -> impl Fn(&RequestPayload) -> ResponseResult {
+        move |_| {
+            Err(ErrorResponse {
+                code: 10,
+                msg: name.to_string(),
+            })
+        }
+    }
+
+    /// Name of the agent which answered with `answer_name`.
+    fn agent_of(response: Result<ResponseResult>) -> String {
+        match response {
+            Ok(Err(ErrorResponse { msg, .. })) => msg,
+            _ => panic!("unexpected response"),
+        }
+    }
+
+    async fn agent_names(manager: &AgentManager) -> Vec<String> {
+        let mut names: Vec<String> = manager
+            .get_client_list()
+            .await
+            .into_iter()
+            .map(|status| status.name)
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    pub async fn retry_on_disconnection() {
+        let (manager, addr) = start_host("strategy = \"round-robin\"").await;
+        FakeAgent::new("agent-1")
+            .hang_up()
+            .on("Score", answer_name("agent-1"))
+            .connect(&addr)
+            .await
+            .unwrap();
+        FakeAgent::new("agent-2")
+            .on("Score", answer_name("agent-2"))
+            .connect(&addr)
+            .await
+            .unwrap();
+        assert!(wait_agents(&manager, 2).await);
+
+        // Either request is sent to agent-1 first, which drops the connection on login, and is retried on agent-2.
+        for _ in 0..2 {
+            assert_eq!(agent_of(manager.request(score("secret")).await), "agent-2");
+        }
+        assert_eq!(agent_names(&manager).await, ["agent-2"]);
+    }
+
+    #[tokio::test]
+    pub async fn retry_on_timeout() {
+        let (manager, addr) = start_host("strategy = \"round-robin\"\ntimeout = 1").await;
+        FakeAgent::new("agent-1")
+            .delay(Duration::from_secs(3))
+            .on("Score", answer_name("agent-1"))
+            .connect(&addr)
+            .await
+            .unwrap();
+        FakeAgent::new("agent-2")
+            .on("Score", answer_name("agent-2"))
+            .connect(&addr)
+            .await
+            .unwrap();
+        assert!(wait_agents(&manager, 2).await);
+
+        for _ in 0..2 {
+            assert_eq!(agent_of(manager.request(score("secret")).await), "agent-2");
+        }
+        // The slow agent is kept in the pool.
+        assert_eq!(agent_names(&manager).await, ["agent-1", "agent-2"]);
+    }
+
+    #[tokio::test]
+    pub async fn keep_agent_on_mismatched_login() {
+        let (manager, addr) = start_host("").await;
+        FakeAgent::new("agent-1")
+            .on("PortalAuth", |_| Ok(ResponsePayload::Pong(String::new())))
+            .on("Score", answer_name("agent-1"))
+            .connect(&addr)
+            .await
+            .unwrap();
+        assert!(wait_agents(&manager, 1).await);
+
+        let error = manager.request(score("secret")).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<HostError>(),
+            Some(HostError::Mismatched)
+        ));
+        assert_eq!(agent_names(&manager).await, ["agent-1"]);
+    }
+
+    #[tokio::test]
+    pub async fn affinity_falls_back_when_full() {
+        let (manager, addr) = start_host("affinity = true\nmax_in_flight = 1").await;
+        for name in ["agent-1", "agent-2"] {
+            FakeAgent::new(name)
+                .delay(Duration::from_millis(100))
+                .on("Score", answer_name(name))
+                .connect(&addr)
+                .await
+                .unwrap();
+        }
+        assert!(wait_agents(&manager, 2).await);
+
+        let preferred = agent_of(manager.request(score("secret")).await);
+
+        // The other agent takes the request only while the preferred one is full.
+        let (r1, r2) = tokio::join!(manager.request(score("secret")), async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            manager.request(score("secret")).await
+        });
+        assert_eq!(agent_of(r1), preferred);
+        assert_ne!(agent_of(r2), preferred);
+
+        assert_eq!(agent_of(manager.request(score("secret")).await), preferred);
+    }
+}
@@ -0,0 +1,121 @@
+//! Challenge-response handshake, which runs on each new agent connection before it joins the pool.
+//!
+//! Host sends a random nonce, and the agent answers with its name and `HMAC-SHA256(key, nonce)`.
+//! The key is looked up in `host.agents` by agent name, or falls back to `host.secret`.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_bincode::{AsyncBincodeStream, AsyncDestination};
+use futures::{SinkExt, StreamExt};
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::config::HostConfig;
+
+use super::HostError;
+
+const NONCE_SIZE: usize = 32;
+/// Time limit of the handshake, and also of the TLS handshake before it.
+pub(super) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Challenge sent by host once the connection is established.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Challenge {
+    pub nonce: Vec<u8>,
+}
+
+/// Agent answer to the challenge.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChallengeResponse {
+    /// Agent name, used to select the key.
+    pub name: String,
+    /// HMAC-SHA256 of the nonce.
+    pub digest: Vec<u8>,
+}
+
+type HandshakeStream<'a, S> =
+    AsyncBincodeStream<&'a mut S, ChallengeResponse, Challenge, AsyncDestination>;
+
+/// Keys to authenticate agents.
+pub struct AgentKeys {
+    secret: Option<String>,
+    agents: HashMap<String, String>,
+}
+
+impl AgentKeys {
+    pub fn from_config(config: &HostConfig) -> Self {
+        Self {
+            secret: config.secret.clone(),
+            agents: config.agents.clone(),
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<&str> {
+        self.agents
+            .get(name)
+            .or_else(|| self.secret.as_ref())
+            .map(String::as_str)
+    }
+}
+
+fn verify_digest(key: &str, nonce: &[u8], digest: &[u8]) -> bool {
+    if let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(key.as_bytes()) {
+        mac.update(nonce);
+        return mac.verify(digest).is_ok();
+    }
+    false
+}
+
+async fn exchange<S>(stream: &mut S, nonce: &[u8]) -> Result<ChallengeResponse>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut framed: HandshakeStream<S> = AsyncBincodeStream::from(stream).for_async();
+
+    framed
+        .send(Challenge {
+            nonce: nonce.to_vec(),
+        })
+        .await?;
+    let response = framed.next().await.ok_or(HostError::Disconnected)??;
+    Ok(response)
+}
+
+/// Run the handshake on a fresh connection, and return the agent name if it passes.
+pub async fn authenticate<S>(stream: &mut S, keys: &AgentKeys) -> Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let nonce: [u8; NONCE_SIZE] = rand::random();
+    let response = tokio::time::timeout(HANDSHAKE_TIMEOUT, exchange(stream, &nonce))
+        .await
+        .map_err(|_| HostError::Timeout)??;
+
+    let key = keys.find(&response.name).ok_or(HostError::AuthFailed)?;
+    if !verify_digest(key, &nonce, &response.digest) {
+        return Err(HostError::AuthFailed.into());
+    }
+    Ok(response.name)
+}
+
+#[cfg(test)]
+mod test {
+    use hmac::{Hmac, Mac, NewMac};
+    use sha2::Sha256;
+
+    #[test]
+    pub fn verify_digest() {
+        let nonce = b"nonce";
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(nonce);
+        let digest = mac.finalize().into_bytes();
+
+        assert!(super::verify_digest("secret", nonce, &digest));
+        assert!(!super::verify_digest("another", nonce, &digest));
+        assert!(!super::verify_digest("secret", b"another", &digest));
+    }
+}
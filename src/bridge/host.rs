@@ -1,19 +1,37 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::atomic::{AtomicI64, AtomicU16, AtomicU32, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_bincode::{AsyncBincodeStream, AsyncDestination};
 use chrono::{DateTime, FixedOffset, Local, NaiveDateTime};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
+use tokio::sync::{Notify, RwLock};
+use tokio_rustls::TlsAcceptor;
 use tokio_tower::multiplex;
 use tokio_tower::multiplex::MultiplexTransport;
+use tower::util::BoxService;
 use tower::{buffer::Buffer, Service, ServiceExt};
 
+use crate::config::HostConfig;
+
+use super::cache::ResponseCache;
+use super::handshake::{self, AgentKeys};
 use super::protocol::Tagged;
-use super::protocol::{RequestFrame, ResponseResult, Tagger};
-use super::{AgentStatus, HostError};
+use super::protocol::{
+    RequestFrame, RequestPayload, ResponseFrame, ResponsePayload, ResponseResult, Tagger,
+};
+use super::record::{Recorder, Replayer};
+use super::session::{SessionKey, SessionStore};
+use super::strategy::{rendezvous, AgentLoad, SelectStrategy};
+use super::tls::load_tls_acceptor;
+use super::{
+    AgentInfo, AgentInfoRequest, AgentStatus, ErrorResponse, HostError, PortalAuthRequest,
+    PortalAuthResponse,
+};
 
 fn on_service_error(e: anyhow::Error) {
     eprintln!("error handling: {:?}", e);
@@ -25,8 +43,15 @@ pub async fn ready<S: Service<RequestFrame>, RequestFrame>(svc: &mut S) -> Resul
     poll_fn(|cx| svc.poll_ready(cx)).await
 }
 
+/// Byte stream to an agent, either plain TCP or TLS.
+pub trait AgentStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AgentStream for T {}
+
+type BoxedStream = Box<dyn AgentStream>;
+
 type BincodeStream =
-    AsyncBincodeStream<TcpStream, Tagged<ResponseResult>, Tagged<RequestFrame>, AsyncDestination>;
+    AsyncBincodeStream<BoxedStream, Tagged<ResponseFrame>, Tagged<RequestFrame>, AsyncDestination>;
 
 type Transport = MultiplexTransport<BincodeStream, Tagger>;
 type MultiplexClient = multiplex::Client<
@@ -35,45 +60,240 @@ type MultiplexClient = multiplex::Client<
     anyhow::Error,
     Tagged<RequestFrame>,
 >;
+/// The multiplex client behind a trait object, so that futures holding it are Send without naming the where
+/// clauses of its Service impl, which the compiler fails to prove for spawned futures.
+type AgentService = BoxService<Tagged<RequestFrame>, Tagged<ResponseFrame>, anyhow::Error>;
+
+/// Result of a request shared by all its waiters.
+type SharedResult = std::result::Result<ResponseResult, Arc<anyhow::Error>>;
+type SharedCall = Shared<BoxFuture<'static, SharedResult>>;
+
+/// Agents reporting a lower protocol version are rejected.
+/// Version 2 adds request ID to request and response frames, and version 3 adds portal session handles.
+const MIN_PROTOCOL_VERSION: u16 = 3;
+
+/// Weight of history in latency EWMA, the new sample takes 1 / LATENCY_DECAY.
+const LATENCY_DECAY: u32 = 8;
+
+/// Request priority. Background requests yield agent capacity to interactive ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Priority {
+    /// Requests from users, who are waiting for the response.
+    Interactive,
+    /// Requests from crawling tasks, like the activity daemon and expense back-fill.
+    Background,
+}
+
+/// Increase the counter if it is below max, return whether it succeeds.
+fn occupy(counter: &AtomicU32, max: u32) -> bool {
+    counter
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+            if n < max {
+                Some(n + 1)
+            } else {
+                None
+            }
+        })
+        .is_ok()
+}
+
+/// Decrease the in-flight counters when a request completes or is cancelled, and wake requests waiting for capacity.
+struct InFlightGuard {
+    in_flight: Arc<AtomicU32>,
+    background: Option<Arc<AtomicU32>>,
+    released: Arc<Notify>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::AcqRel);
+        if let Some(background) = &self.background {
+            background.fetch_sub(1, Ordering::AcqRel);
+        }
+        self.released.notify_waiters();
+    }
+}
 
 #[derive(Clone)]
 struct Client {
+    name: String,
     address: String,
+    intranet_addr: String,
+    /// Protocol version reported by the agent.
+    version: u16,
+    /// Request kinds the agent supports.
+    capabilities: Arc<HashSet<String>>,
     count: Arc<AtomicU32>,
+    in_flight: Arc<AtomicU32>,
+    /// Background requests in flight, which are also counted in `in_flight`.
+    background: Arc<AtomicU32>,
+    /// Latency EWMA in microseconds.
+    latency: Arc<AtomicU32>,
     last_use: Arc<AtomicI64>,
-    client: Buffer<MultiplexClient, Tagged<RequestFrame>>,
+    client: Buffer<AgentService, Tagged<RequestFrame>>,
 }
 
 impl Client {
-    pub fn new(address: String, stream: TcpStream) -> Self {
+    pub fn new(name: String, address: String, stream: BoxedStream) -> Self {
         let stream: BincodeStream = AsyncBincodeStream::from(stream).for_async();
         let transport: Transport = multiplex::MultiplexTransport::new(stream, Tagger::default());
-        let client = multiplex::Client::with_error_handler(transport, on_service_error);
+        let client: MultiplexClient = multiplex::Client::with_error_handler(transport, on_service_error);
 
-        let buffered_client = Buffer::new(client, 1024);
+        let buffered_client = Buffer::new(BoxService::new(client), 1024);
 
         let current_time = Local::now().timestamp_millis();
         Self {
+            name,
             address,
+            intranet_addr: String::new(),
+            version: 0,
+            capabilities: Arc::new(HashSet::new()),
             count: Arc::new(AtomicU32::default()),
+            in_flight: Arc::new(AtomicU32::default()),
+            background: Arc::new(AtomicU32::default()),
+            latency: Arc::new(AtomicU32::default()),
             last_use: Arc::new(AtomicI64::new(current_time)),
             client: buffered_client,
         }
     }
 
-    pub async fn request(&mut self, request: RequestFrame) -> Result<ResponseResult> {
+    pub fn load(&self, seq: u16) -> AgentLoad {
+        AgentLoad {
+            seq,
+            requests: self.count.load(Ordering::Acquire),
+            in_flight: self.in_flight.load(Ordering::Acquire),
+            latency: self.latency.load(Ordering::Acquire),
+        }
+    }
+
+    fn update_latency(&self, elapsed: Duration) {
+        let sample = elapsed.as_micros().min(u32::MAX as u128) as u32;
+        let last = self.latency.load(Ordering::Acquire);
+        let current = if last == 0 {
+            sample
+        } else {
+            last - last / LATENCY_DECAY + sample / LATENCY_DECAY
+        };
+        // Keep it non-zero, as zero means the agent has not been measured.
+        self.latency.store(current.max(1), Ordering::Release);
+    }
+
+    /// Send a request without updating statistics, used for heartbeat and other internal requests.
+    async fn call(&self, request: RequestFrame) -> Result<ResponseResult> {
         let mut client = self.client.clone();
         let ready_client = client.ready().await.map_err(|_| HostError::Disconnected)?;
 
+        let request_id = request.request_id().to_string();
         let response = ready_client
             .call(Tagged::<RequestFrame>::from(request))
             .await
             .map_err(|_| HostError::Timeout)?;
+        let frame = response.v;
+        if frame.request_id != request_id {
+            eprintln!(
+                "Agent {} answered request {} with ID {}",
+                self.name, request_id, frame.request_id
+            );
+        }
+        Ok(frame.payload)
+    }
+
+    /// Whether the agent can handle the given kind of request.
+    /// Ping and AgentInfo are part of the connection procedure, so every agent supports them.
+    fn supports(&self, kind: &str) -> bool {
+        matches!(kind, "Ping" | "AgentInfo") || self.capabilities.contains(kind)
+    }
+
+    fn set_info(&mut self, info: AgentInfo) {
+        self.intranet_addr = info.intranet_addr;
+        self.version = info.protocol_version;
+        self.capabilities = Arc::new(info.capabilities.into_iter().collect());
+    }
+
+    /// Whether the agent can take one more request of the priority. Background requests are limited,
+    /// and leave `reserved` in-flight slots to interactive ones.
+    fn admits(
+        &self,
+        priority: Priority,
+        max_in_flight: u32,
+        max_background: u32,
+        reserved: u32,
+    ) -> bool {
+        let in_flight = self.in_flight.load(Ordering::Acquire);
+        match priority {
+            Priority::Interactive => in_flight < max_in_flight,
+            Priority::Background => {
+                let background = self.background.load(Ordering::Acquire);
+                in_flight < max_in_flight.saturating_sub(reserved) && background < max_background
+            }
+        }
+    }
 
+    /// Occupy a slot of in-flight requests, and a background slot for background requests.
+    /// Return None if the agent is full.
+    fn acquire(
+        &self,
+        priority: Priority,
+        max_in_flight: u32,
+        max_background: u32,
+        reserved: u32,
+        released: &Arc<Notify>,
+    ) -> Option<InFlightGuard> {
+        let max_in_flight = match priority {
+            Priority::Interactive => max_in_flight,
+            Priority::Background => max_in_flight.saturating_sub(reserved),
+        };
+        if !occupy(&self.in_flight, max_in_flight) {
+            return None;
+        }
+        let background = match priority {
+            Priority::Interactive => None,
+            Priority::Background => {
+                if !occupy(&self.background, max_background) {
+                    self.in_flight.fetch_sub(1, Ordering::AcqRel);
+                    return None;
+                }
+                Some(self.background.clone())
+            }
+        };
+        Some(InFlightGuard {
+            in_flight: self.in_flight.clone(),
+            background,
+            released: released.clone(),
+        })
+    }
+
+    pub async fn request(&mut self, request: RequestFrame) -> Result<ResponseResult> {
+        let start = Instant::now();
+
+        let response = self.call(request).await?;
+
+        self.update_latency(start.elapsed());
         self.count.fetch_add(1, Ordering::SeqCst);
         self.last_use
             .store(Local::now().timestamp_millis(), Ordering::Release);
-        Ok(response.v)
+        Ok(response)
+    }
+
+    /// Ask the agent for its information, and give up if it does not answer in time.
+    async fn query_info(&self, timeout: Duration) -> Result<AgentInfo> {
+        let request = RequestFrame::new(RequestPayload::AgentInfo(AgentInfoRequest));
+        let response = tokio::time::timeout(timeout, self.call(request))
+            .await
+            .map_err(|_| HostError::Timeout)?;
+
+        match response?? {
+            ResponsePayload::Credential(info) => Ok(info),
+            _ => Err(HostError::Mismatched.into()),
+        }
+    }
+
+    /// Send a ping, and return whether the agent answers in time.
+    async fn ping(&self, timeout: Duration) -> bool {
+        let request = RequestFrame::new(RequestPayload::Ping("heartbeat".to_string()));
+        let response = tokio::time::timeout(timeout, self.call(request)).await;
+
+        matches!(response, Ok(Ok(Ok(ResponsePayload::Pong(_)))))
     }
 }
 
@@ -82,37 +302,203 @@ pub struct AgentManager {
     agent_seq: Arc<AtomicU16>,
     bind_addr: String,
     clients: Arc<RwLock<HashMap<u16, Client>>>,
+    strategy: Arc<dyn SelectStrategy>,
+    /// Select agents by account for requests carrying one.
+    affinity: bool,
+    retry: u8,
+    heartbeat_interval: Duration,
+    max_missed_heartbeat: u8,
+    max_agents: usize,
+    max_in_flight: u32,
+    max_background: u32,
+    /// In-flight slots on each agent which background requests leave to interactive ones.
+    reserved: u32,
+    /// Priority of requests sent by this handle.
+    priority: Priority,
+    /// Notified when an in-flight slot is released.
+    released: Arc<Notify>,
+    default_timeout: Duration,
+    timeouts: Arc<HashMap<String, Duration>>,
+    cache: Arc<ResponseCache>,
+    /// Requests in flight which can be shared, indexed by priority and request digest. Requests of different
+    /// priorities are not shared, or an interactive request could wait behind a background one.
+    flights: Arc<Mutex<HashMap<(Priority, Vec<u8>), SharedCall>>>,
+    keys: Arc<AgentKeys>,
+    acceptor: Option<TlsAcceptor>,
+    /// Portal session handles on agents.
+    sessions: Arc<SessionStore>,
+    recorder: Option<Arc<Recorder>>,
+    /// Answer requests from records instead of agents if set.
+    replayer: Option<Arc<Replayer>>,
 }
 
 impl AgentManager {
-    pub fn new(bind_addr: &str) -> Self {
+    pub fn new(config: &HostConfig) -> Self {
         Self {
             agent_seq: Arc::new(AtomicU16::default()),
-            bind_addr: bind_addr.to_string(),
+            bind_addr: config.bind.clone(),
             clients: Arc::new(RwLock::new(HashMap::new())),
+            strategy: Arc::from(config.strategy.build()),
+            affinity: config.affinity,
+            retry: config.retry,
+            heartbeat_interval: Duration::from_secs(config.heartbeat_interval.get()),
+            max_missed_heartbeat: config.max_missed_heartbeat,
+            max_agents: config.max as usize,
+            max_in_flight: config.max_in_flight,
+            max_background: config.background_in_flight,
+            reserved: config.reserved_in_flight,
+            priority: Priority::Interactive,
+            released: Arc::new(Notify::new()),
+            default_timeout: Duration::from_secs(config.timeout),
+            timeouts: Arc::new(
+                config
+                    .timeouts
+                    .iter()
+                    .map(|(kind, &secs)| (kind.clone(), Duration::from_secs(secs)))
+                    .collect(),
+            ),
+            cache: Arc::new(ResponseCache::new(&config.cache_ttl)),
+            flights: Arc::new(Mutex::new(HashMap::new())),
+            keys: Arc::new(AgentKeys::from_config(config)),
+            acceptor: config
+                .tls
+                .as_ref()
+                .map(|tls| load_tls_acceptor(tls).expect("Could not load TLS config for agents.")),
+            sessions: Arc::new(SessionStore::default()),
+            recorder: config
+                .record
+                .as_ref()
+                .map(|path| Arc::new(Recorder::open(path).expect("Could not open agent record file."))),
+            replayer: config
+                .replay
+                .as_ref()
+                .map(|path| Arc::new(Replayer::load(path).expect("Could not load agent record file."))),
         }
     }
 
-    async fn add_client(&self, client: Client) {
-        let last_agent_seq = self.agent_seq.fetch_add(1, Ordering::Acquire);
+    /// A handle sharing the same agent pool, sending requests with background priority.
+    pub fn background(&self) -> Self {
+        Self {
+            priority: Priority::Background,
+            ..self.clone()
+        }
+    }
+
+    /// Remove all cached responses, return the count removed.
+    pub async fn purge_cache(&self) -> usize {
+        self.cache.purge().await
+    }
+
+    fn timeout_of(&self, request: &RequestFrame) -> Duration {
+        self.timeouts
+            .get(request.kind())
+            .copied()
+            .unwrap_or(self.default_timeout)
+    }
+
+    /// Add the agent to the pool, unless the pool is full. The count checked before the handshake may be
+    /// stale by now, since other agents could join in the meantime.
+    async fn add_client(&self, client: Client) -> Result<u16> {
         let mut clients = self.clients.write().await;
+        if clients.len() >= self.max_agents {
+            return Err(anyhow!("too many agents, max = {}", self.max_agents));
+        }
+        let last_agent_seq = self.agent_seq.fetch_add(1, Ordering::Acquire);
         clients.insert(last_agent_seq, client);
+        Ok(last_agent_seq)
     }
 
     async fn remove_client(&self, agent_seq: u16) {
         let mut clients = self.clients.write().await;
         clients.remove(&agent_seq);
+        self.sessions.forget_agent(agent_seq);
     }
 
-    async fn get_client(&self) -> Option<(u16, Client)> {
-        use rand::{rngs::StdRng, seq::IteratorRandom, SeedableRng};
-
-        let mut rng = StdRng::from_entropy();
+    /// Select an agent which supports the request kind and admits the request, and occupy one of its
+    /// in-flight slots. Agents in `tried`, which have failed the request, are skipped.
+    async fn get_client(
+        &self,
+        request_frame: &RequestFrame,
+        tried: &[u16],
+    ) -> std::result::Result<(u16, Client, InFlightGuard), HostError> {
         let clients = self.clients.read().await;
-        if let Some((seq, client)) = clients.iter().choose(&mut rng) {
-            return Some((*seq, client.clone()));
+        if clients.is_empty() {
+            return Err(HostError::NoAgentAvailable);
+        }
+        let capable: Vec<(&u16, &Client)> = clients
+            .iter()
+            .filter(|(seq, client)| client.supports(request_frame.kind()) && !tried.contains(seq))
+            .collect();
+        if capable.is_empty() {
+            return Err(HostError::Unsupported);
+        }
+        let admits = |client: &Client| {
+            client.admits(
+                self.priority,
+                self.max_in_flight,
+                self.max_background,
+                self.reserved,
+            )
+        };
+
+        // Requests of the same account go to the same agent, so that it can reuse the portal session. The agent
+        // is chosen among all capable ones, so that the choice does not change with load, and others take the
+        // request only when it is full.
+        let preferred = request_frame
+            .account()
+            .filter(|_| self.affinity)
+            .and_then(|account| {
+                let nodes: Vec<(u16, &str)> = capable
+                    .iter()
+                    .map(|(&seq, client)| (seq, client.name.as_str()))
+                    .collect();
+                rendezvous(account, &nodes)
+            })
+            .filter(|seq| admits(&clients[seq]));
+        let selected = preferred.or_else(|| {
+            let candidates: Vec<AgentLoad> = capable
+                .iter()
+                .filter(|(_, client)| admits(client))
+                .map(|(&seq, client)| client.load(seq))
+                .collect();
+            self.strategy.select(&candidates)
+        });
+        let seq = selected.ok_or(HostError::Overloaded)?;
+        let client = clients[&seq].clone();
+        let guard = client
+            .acquire(
+                self.priority,
+                self.max_in_flight,
+                self.max_background,
+                self.reserved,
+                &self.released,
+            )
+            .ok_or(HostError::Overloaded)?;
+        Ok((seq, client, guard))
+    }
+
+    /// Like `get_client`, but background requests wait for capacity until the deadline instead of
+    /// failing with Overloaded.
+    async fn wait_client(
+        &self,
+        request_frame: &RequestFrame,
+        tried: &[u16],
+        deadline: Instant,
+    ) -> std::result::Result<(u16, Client, InFlightGuard), HostError> {
+        loop {
+            // Create the notification before checking, so that no release is missed in between.
+            let released = self.released.notified();
+            match self.get_client(request_frame, tried).await {
+                Err(HostError::Overloaded) if self.priority == Priority::Background => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(HostError::Overloaded);
+                    }
+                    let _ = tokio::time::timeout(deadline - now, released).await;
+                }
+                result => return result,
+            }
         }
-        None
     }
 
     pub async fn get_client_list(&self) -> Vec<AgentStatus> {
@@ -125,40 +511,291 @@ impl AgentManager {
                 let last_use = DateTime::from_utc(last_use_utc, FixedOffset::east(8 * 3600));
                 AgentStatus {
                     seq,
-                    name: "".to_string(),
-                    intranet_addr: "".to_string(),
+                    name: client.name.clone(),
+                    intranet_addr: client.intranet_addr.clone(),
                     external_addr: client.address.clone(),
+                    version: client.version,
+                    capabilities: client.capabilities.iter().cloned().collect(),
                     requests: client.count.load(Ordering::Acquire),
+                    in_flight: client.in_flight.load(Ordering::Acquire),
+                    background: client.background.load(Ordering::Acquire),
+                    latency: client.latency.load(Ordering::Acquire),
                     last_use,
                 }
             })
             .collect()
     }
 
+    /// Set up TLS if configured, and join the agent to the pool.
+    async fn accept(&self, stream: TcpStream, source_addr: String) -> Result<()> {
+        if self.clients.read().await.len() >= self.max_agents {
+            return Err(anyhow!("too many agents, max = {}", self.max_agents));
+        }
+        let stream: BoxedStream = match &self.acceptor {
+            Some(acceptor) => {
+                // Clients which connect but never finish the TLS handshake should not hold the task forever.
+                let tls_stream =
+                    tokio::time::timeout(handshake::HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+                        .await
+                        .map_err(|_| HostError::Timeout)??;
+                Box::new(tls_stream)
+            }
+            None => Box::new(stream),
+        };
+        self.join(stream, source_addr).await
+    }
+
+    /// Run the handshake on an established stream, then add the agent to the pool.
+    /// Streams may come from the TCP listener or the WebSocket endpoint.
+    pub(super) async fn join(&self, mut stream: BoxedStream, source_addr: String) -> Result<()> {
+        if self.clients.read().await.len() >= self.max_agents {
+            return Err(anyhow!("too many agents, max = {}", self.max_agents));
+        }
+        // Agents must pass the handshake before joining the pool.
+        let name = handshake::authenticate(&mut stream, &self.keys).await?;
+        let mut client = Client::new(name, source_addr, stream);
+        // Same time limit as heartbeats, or an agent which never answers would hold the task forever.
+        let info = client.query_info(self.heartbeat_interval).await?;
+        if info.protocol_version < MIN_PROTOCOL_VERSION {
+            return Err(anyhow!(
+                "protocol version {} is not supported, {} at least",
+                info.protocol_version,
+                MIN_PROTOCOL_VERSION
+            ));
+        }
+        client.set_info(info);
+
+        let seq = self.add_client(client.clone()).await?;
+        tokio::spawn(self.clone().keep_alive(seq, client));
+        Ok(())
+    }
+
+    /// Ping the agent periodically, and evict it when it misses too many heartbeats in a row.
+    async fn keep_alive(self, seq: u16, client: Client) {
+        let mut interval = tokio::time::interval(self.heartbeat_interval);
+        let mut missed = 0;
+
+        // The first tick completes immediately.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            // Stop if the agent has been removed elsewhere.
+            if !self.clients.read().await.contains_key(&seq) {
+                return;
+            }
+            if client.ping(self.heartbeat_interval).await {
+                missed = 0;
+                continue;
+            }
+            missed += 1;
+            if missed >= self.max_missed_heartbeat {
+                eprintln!(
+                    "Agent {} ({}) missed {} heartbeats, removed.",
+                    seq, client.name, missed
+                );
+                self.remove_client(seq).await;
+                return;
+            }
+        }
+    }
+
     pub async fn listen(&self) {
         // Bind a server socket
         let listener = TcpListener::bind(&self.bind_addr)
             .await
             .expect("Could not bind to server.");
 
+        self.serve(listener).await;
+    }
+
+    /// Accept agents on the listener.
+    pub async fn serve(&self, listener: TcpListener) {
         while let Ok((s, source_addr)) = listener.accept().await {
-            let client = Client::new(source_addr.to_string(), s);
-            self.add_client(client).await;
+            let manager = self.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = manager.accept(s, source_addr.to_string()).await {
+                    eprintln!("Agent {} rejected: {}", source_addr, e);
+                }
+            });
         }
     }
 
     pub async fn request(&self, request_frame: RequestFrame) -> Result<ResponseResult> {
-        let client = self.get_client().await;
+        if let Some(replayer) = &self.replayer {
+            return replayer.get(&request_frame).ok_or_else(|| {
+                anyhow!(
+                    "No recorded response for request {} ({})",
+                    request_frame.request_id(),
+                    request_frame.kind()
+                )
+            });
+        }
+        if let Some(payload) = self.cache.get(&request_frame).await {
+            return Ok(Ok(payload));
+        }
+        let response = match request_frame.digest() {
+            Some(key) if request_frame.is_shared() => {
+                self.request_shared(key, request_frame.clone()).await?
+            }
+            _ => self.request_agent(&request_frame).await?,
+        };
+        if let Ok(payload) = &response {
+            self.cache.put(&request_frame, payload).await;
+        }
+        Ok(response)
+    }
 
-        if let Some((seq, mut client)) = client {
-            let result = client.request(request_frame).await;
-            // Remove client if error occurred in transport layer, like agent disconnection.
-            if result.is_err() {
-                self.remove_client(seq).await;
+    /// Send the request, or wait for an identical one in flight and share its response.
+    ///
+    /// The request runs in its own task, which removes the flight when done. So it completes and is cleaned
+    /// up even if all waiters are cancelled, like when the HTTP clients disconnect.
+    async fn request_shared(&self, key: Vec<u8>, request_frame: RequestFrame) -> Result<ResponseResult> {
+        let key = (self.priority, key);
+        let call = {
+            let mut flights = self.flights.lock().unwrap();
+            flights
+                .entry(key.clone())
+                .or_insert_with(|| {
+                    let manager = self.clone();
+                    let task = tokio::spawn(async move {
+                        let result = manager.request_agent(&request_frame).await.map_err(Arc::new);
+                        manager.flights.lock().unwrap().remove(&key);
+                        result
+                    });
+                    task.map(|joined| joined.unwrap_or_else(|e| Err(Arc::new(anyhow!("{}", e)))))
+                        .boxed()
+                        .shared()
+                })
+                .clone()
+        };
+
+        call.await.map_err(|e| match e.downcast_ref::<HostError>() {
+            Some(&host_error) => host_error.into(),
+            None => anyhow!("{}", e),
+        })
+    }
+
+    /// Log in to the portal on the agent, and return the session handle.
+    /// Errors reported by the agent, like a wrong password, are returned as the inner error.
+    async fn login(
+        &self,
+        client: &mut Client,
+        request_frame: &RequestFrame,
+    ) -> Result<std::result::Result<String, ErrorResponse>> {
+        let payload = request_frame.payload();
+        let data = PortalAuthRequest {
+            account: payload.account().unwrap_or_default().to_string(),
+            credential: payload.secret().unwrap_or_default().to_string(),
+        };
+        let mut request = RequestFrame::new(RequestPayload::PortalAuth(data));
+        request.set_request_id(request_frame.request_id());
+
+        match client.request(request).await? {
+            Ok(ResponsePayload::PortalAuth(PortalAuthResponse::Session(handle))) => Ok(Ok(handle)),
+            Ok(_) => Err(HostError::Mismatched.into()),
+            Err(e) => Ok(Err(e)),
+        }
+    }
+
+    /// Send the request to the agent. Passwords are replaced by the portal session handle of the account
+    /// on the agent, which is obtained by logging in for the first time and again after it expires.
+    async fn send(
+        &self,
+        seq: u16,
+        client: &mut Client,
+        request_frame: &RequestFrame,
+    ) -> Result<ResponseResult> {
+        let key = match SessionKey::of(seq, request_frame) {
+            Some(key) => key,
+            None => return client.request(request_frame.clone()).await,
+        };
+        // Whether the handle is just obtained, so that it can not expire.
+        let mut fresh = false;
+
+        loop {
+            let handle = match self.sessions.get(&key) {
+                Some(handle) => handle,
+                None => {
+                    fresh = true;
+                    match self.login(client, request_frame).await? {
+                        Ok(handle) => {
+                            self.sessions.put(key.clone(), handle.clone());
+                            handle
+                        }
+                        Err(e) => return Ok(Err(e)),
+                    }
+                }
+            };
+            match client.request(request_frame.with_session(&handle)).await? {
+                Err(e) if e.code == HostError::SessionExpired as u16 && !fresh => {
+                    self.sessions.remove(&key);
+                }
+                response => return Ok(response),
             }
-            result
+        }
+    }
+
+    async fn request_agent(&self, request_frame: &RequestFrame) -> Result<ResponseResult> {
+        let timeout = self.timeout_of(request_frame);
+        // Only idempotent requests are retried, or a request like joining an activity may be executed twice.
+        let mut remain = if request_frame.is_idempotent() {
+            self.retry
         } else {
-            Err(HostError::NoAgentAvailable.into())
+            0
+        };
+        let mut tried = Vec::new();
+        let mut last_error = None;
+
+        loop {
+            let deadline = Instant::now() + timeout;
+            let (seq, mut client, _guard) = match self.wait_client(request_frame, &tried, deadline).await
+            {
+                Ok(selected) => selected,
+                // Report the failure of the last agent if there is no other agent to retry on.
+                Err(e) => return Err(last_error.unwrap_or_else(|| e.into())),
+            };
+            tried.push(seq);
+            let result = tokio::time::timeout(timeout, self.send(seq, &mut client, request_frame)).await;
+            let error = match result {
+                // The agent is slow but still alive, so keep it in the pool.
+                Err(_) => {
+                    eprintln!(
+                        "Request {} ({}) timed out on agent {}",
+                        request_frame.request_id(),
+                        request_frame.kind(),
+                        seq
+                    );
+                    HostError::Timeout.into()
+                }
+                Ok(Ok(response)) => {
+                    if let Some(recorder) = &self.recorder {
+                        recorder.write(request_frame, &response).await;
+                    }
+                    return Ok(response);
+                }
+                // The agent answered an unexpected payload, like on login, so the connection is still healthy.
+                Ok(Err(e)) if matches!(e.downcast_ref::<HostError>(), Some(HostError::Mismatched)) => {
+                    return Err(e);
+                }
+                Ok(Err(e)) => {
+                    // Remove client if error occurred in transport layer, like agent disconnection.
+                    self.remove_client(seq).await;
+                    e
+                }
+            };
+            if remain == 0 {
+                return Err(error);
+            }
+            eprintln!(
+                "Request {} failed on agent {}, retry on another agent ({} left): {}",
+                request_frame.request_id(),
+                seq,
+                remain,
+                error
+            );
+            remain -= 1;
+            last_error = Some(error);
         }
     }
 }
@@ -8,38 +8,46 @@ use strum_macros::EnumString;
 use crate::error::{ApiError, Result};
 use crate::models::CommonError;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AgentInfoRequest;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AgentInfo {
     pub name: String,
+    /// Agent address in the campus network.
+    pub intranet_addr: String,
+    /// Protocol version implemented by the agent.
+    pub protocol_version: u16,
+    /// Request kinds supported, named after `RequestPayload` variants like "TimeTable".
+    pub capabilities: Vec<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PortalAuthRequest {
     pub account: String,
     pub credential: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub enum PortalAuthResponse {
     Ok,
     Err(String),
+    /// Login succeeded, with the session handle to use in later requests of the account.
+    Session(String),
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ScScoreItemRequest {
     pub account: String,
     pub passwd: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ScScoreItem {
     pub activity_id: i32,
@@ -56,14 +64,14 @@ pub struct SaveScScore {
     pub amount: f32,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ScActivityRequest {
     pub account: String,
     pub passwd: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ScActivityItem {
     pub activity_id: i32,
@@ -90,7 +98,7 @@ pub struct ScDetail {
     pub category: i32,
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Clone, Deserialize)]
 pub struct ActivityListRequest {
     /// Count of activities per page.
     pub count: u16,
@@ -106,7 +114,7 @@ pub struct WrongScActivity {
     pub activity_id_category: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ActivityDetailRequest {
     /// Activity id in sc.sit.edu.cn
@@ -122,7 +130,7 @@ pub struct Activity {
 }
 
 /// Activity link, used for list recent activities.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ActivityDetail {
     /// Activity id
@@ -155,14 +163,14 @@ pub struct ActivityDetail {
     pub images: Vec<ScImages>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct ScImages {
     pub new_name: String,
     pub old_name: String,
     pub content: Vec<u8>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ScJoinRequest {
     pub account: String,
     pub password: String,
@@ -198,7 +206,7 @@ pub fn trans_to_year(year: String) -> Result<SchoolYear> {
     Ok(SchoolYear::SomeYear(first_year))
 }
 
-#[derive(Clone, Debug, Deserialize, serde_repr::Serialize_repr, PartialEq)]
+#[derive(Clone, Debug, serde_repr::Deserialize_repr, serde_repr::Serialize_repr, PartialEq)]
 #[repr(u8)]
 pub enum Semester {
     All = 0,
@@ -216,7 +224,7 @@ pub fn trans_to_semester(number: i32) -> Semester {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct MajorRequest {
     pub entrance_year: SchoolYear,
     pub account: String,
@@ -240,7 +248,7 @@ pub struct Major {
     direction: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TimeTableRequest {
     pub account: String,
     pub passwd: String,
@@ -275,7 +283,7 @@ pub struct Course {
     pub course_id: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ScoreRequest {
     pub account: String,
     pub passwd: String,
@@ -302,7 +310,7 @@ pub struct Score {
     pub(crate) credit: f32,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ScoreDetailRequest {
     pub account: String,
     pub password: String,
@@ -364,7 +372,7 @@ pub struct ScScoreSummary {
 }
 
 /// 搜索方式
-#[derive(Debug, Serialize, Deserialize, EnumString)]
+#[derive(Debug, Clone, Serialize, Deserialize, EnumString)]
 #[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
 pub enum SearchWay {
     /// 按任意词查询
@@ -392,7 +400,7 @@ pub enum SearchWay {
 }
 
 /// 排序规则
-#[derive(Debug, Serialize, Deserialize, EnumString)]
+#[derive(Debug, Clone, Serialize, Deserialize, EnumString)]
 #[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
 pub enum SortWay {
     /// 匹配度
@@ -421,7 +429,7 @@ pub enum SortWay {
     Volume,
 }
 
-#[derive(Debug, Serialize, Deserialize, EnumString)]
+#[derive(Debug, Clone, Serialize, Deserialize, EnumString)]
 #[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
 pub enum SortOrder {
     /// 升序排序
@@ -430,7 +438,7 @@ pub enum SortOrder {
     Desc,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SearchLibraryRequest {
     /// 搜索关键字
     pub keyword: String,
@@ -447,13 +455,13 @@ pub struct SearchLibraryRequest {
 }
 
 /// 馆藏信息检索
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BookHoldingRequest {
     pub book_id_list: Vec<String>,
 }
 
 /// 图书信息
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Book {
     /// 图书号
@@ -473,7 +481,7 @@ pub struct Book {
 }
 
 /// 检索结果
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SearchLibraryResult {
     /// 检索总结果数(所有页面的结果总数)
@@ -559,7 +567,7 @@ pub struct ExpenseRequest {
     pub end_time: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExamArrangeRequest {
     pub account: String,
     pub password: String,
@@ -1,12 +1,18 @@
 use std::pin::Pin;
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use strum_macros::IntoStaticStr;
 use tokio_tower::multiplex;
 
 use crate::bridge::model::*;
+use crate::request_id;
+
+/// Placeholder of redacted passwords.
+pub const REDACTED: &str = "<redacted>";
 
 /// Response payload
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, IntoStaticStr)]
 pub enum RequestPayload {
     None,
     Ping(String),
@@ -27,8 +33,130 @@ pub enum RequestPayload {
     ExamArrange(ExamArrangeRequest),
 }
 
+impl RequestPayload {
+    /// Kind of the payload, which is the variant name like "Ping".
+    pub fn kind(&self) -> &'static str {
+        self.into()
+    }
+
+    /// Whether the request can be sent again safely, for example to another agent after a transport error.
+    /// Requests which change the state on the campus side, like joining an activity, are not.
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            RequestPayload::Ping(_)
+                | RequestPayload::AgentInfo(_)
+                | RequestPayload::PortalAuth(_)
+                | RequestPayload::ActivityList(_)
+                | RequestPayload::ActivityDetail(_)
+                | RequestPayload::ScScoreDetail(_)
+                | RequestPayload::ScActivityDetail(_)
+                | RequestPayload::MajorList(_)
+                | RequestPayload::TimeTable(_)
+                | RequestPayload::Score(_)
+                | RequestPayload::ScoreDetail(_)
+                | RequestPayload::SearchLibrary(_)
+                | RequestPayload::BookHoldingInfo(_)
+                | RequestPayload::CardExpense(_)
+                | RequestPayload::ExamArrange(_)
+        )
+    }
+
+    /// Whether the request carries nothing specific to a user, so that identical ones may share a response.
+    pub fn is_shared(&self) -> bool {
+        matches!(
+            self,
+            RequestPayload::ActivityList(_)
+                | RequestPayload::ActivityDetail(_)
+                | RequestPayload::SearchLibrary(_)
+                | RequestPayload::BookHoldingInfo(_)
+        )
+    }
+
+    /// Account of the student, for requests which log in to campus systems.
+    pub fn account(&self) -> Option<&str> {
+        let account = match self {
+            RequestPayload::PortalAuth(r) => &r.account,
+            RequestPayload::ScScoreDetail(r) => &r.account,
+            RequestPayload::ScActivityDetail(r) => &r.account,
+            RequestPayload::ScActivityJoin(r) => &r.account,
+            RequestPayload::MajorList(r) => &r.account,
+            RequestPayload::TimeTable(r) => &r.account,
+            RequestPayload::Score(r) => &r.account,
+            RequestPayload::ScoreDetail(r) => &r.account,
+            RequestPayload::CardExpense(r) => &r.account,
+            RequestPayload::ExamArrange(r) => &r.account,
+            _ => return None,
+        };
+        Some(account)
+    }
+
+    /// Password or credential carried by the request, if any.
+    pub fn secret(&self) -> Option<&str> {
+        let secret = match self {
+            RequestPayload::PortalAuth(r) => &r.credential,
+            RequestPayload::ScScoreDetail(r) => &r.passwd,
+            RequestPayload::ScActivityDetail(r) => &r.passwd,
+            RequestPayload::ScActivityJoin(r) => &r.password,
+            RequestPayload::MajorList(r) => &r.passwd,
+            RequestPayload::TimeTable(r) => &r.passwd,
+            RequestPayload::Score(r) => &r.passwd,
+            RequestPayload::ScoreDetail(r) => &r.password,
+            RequestPayload::CardExpense(r) => &r.password,
+            RequestPayload::ExamArrange(r) => &r.password,
+            _ => return None,
+        };
+        Some(secret)
+    }
+
+    fn secret_mut(&mut self) -> Option<&mut String> {
+        match self {
+            RequestPayload::PortalAuth(r) => Some(&mut r.credential),
+            RequestPayload::ScScoreDetail(r) => Some(&mut r.passwd),
+            RequestPayload::ScActivityDetail(r) => Some(&mut r.passwd),
+            RequestPayload::ScActivityJoin(r) => Some(&mut r.password),
+            RequestPayload::MajorList(r) => Some(&mut r.passwd),
+            RequestPayload::TimeTable(r) => Some(&mut r.passwd),
+            RequestPayload::Score(r) => Some(&mut r.passwd),
+            RequestPayload::ScoreDetail(r) => Some(&mut r.password),
+            RequestPayload::CardExpense(r) => Some(&mut r.password),
+            RequestPayload::ExamArrange(r) => Some(&mut r.password),
+            _ => None,
+        }
+    }
+
+    /// Copy of the payload with the password replaced.
+    fn replace_secret(&self, replacement: &str) -> Self {
+        let mut payload = self.clone();
+        if let Some(secret) = payload.secret_mut() {
+            *secret = replacement.to_string();
+        }
+        payload
+    }
+
+    /// Copy of the payload with the password redacted, so that it can be written to files.
+    pub fn redacted(&self) -> Self {
+        self.replace_secret(REDACTED)
+    }
+
+    /// SHA-256 of the serialized payload.
+    pub fn digest(&self) -> Option<Vec<u8>> {
+        let bytes = bincode::serialize(self).ok()?;
+        Some(Sha256::digest(&bytes).to_vec())
+    }
+
+    /// Whether the response can be cached. Authentication and connection checks are always sent to agents.
+    pub fn is_cacheable(&self) -> bool {
+        self.is_idempotent()
+            && !matches!(
+                self,
+                RequestPayload::Ping(_) | RequestPayload::AgentInfo(_) | RequestPayload::PortalAuth(_)
+            )
+    }
+}
+
 /// Response payload
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ResponsePayload {
     None,
     Pong(String),
@@ -50,7 +178,7 @@ pub enum ResponsePayload {
 }
 
 /// Error code and message to response
-#[derive(Debug, Deserialize, thiserror::Error)]
+#[derive(Debug, Clone, Serialize, Deserialize, thiserror::Error)]
 #[error("{} ({})", msg, code)]
 pub struct ErrorResponse {
     pub code: u16,
@@ -61,20 +189,80 @@ pub struct ErrorResponse {
 /// Otherwise, an Err(ErrorResponse) representing an error occurred when executing the operation by agent.
 pub type ResponseResult = std::result::Result<ResponsePayload, ErrorResponse>;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestFrame {
     payload: RequestPayload,
+    /// ID of the HTTP request which causes this one, or a new one for background tasks.
+    request_id: String,
+    /// Portal session handle on the agent, used instead of the password in payload.
+    session: Option<String>,
 }
 
 impl RequestFrame {
     pub fn new(payload: RequestPayload) -> Self {
-        Self { payload }
+        Self {
+            payload,
+            request_id: request_id::current().unwrap_or_else(request_id::generate),
+            session: None,
+        }
+    }
+
+    /// Copy of the request, which carries the session handle and no password.
+    pub fn with_session(&self, handle: &str) -> Self {
+        Self {
+            payload: self.payload.replace_secret(""),
+            request_id: self.request_id.clone(),
+            session: Some(handle.to_string()),
+        }
+    }
+
+    pub fn session(&self) -> Option<&str> {
+        self.session.as_deref()
+    }
+
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    pub fn set_request_id(&mut self, request_id: &str) {
+        self.request_id = request_id.to_string();
+    }
+
+    pub fn payload(&self) -> &RequestPayload {
+        &self.payload
+    }
+
+    pub fn kind(&self) -> &'static str {
+        self.payload.kind()
+    }
+
+    pub fn is_idempotent(&self) -> bool {
+        self.payload.is_idempotent()
+    }
+
+    pub fn is_cacheable(&self) -> bool {
+        self.payload.is_cacheable()
+    }
+
+    pub fn is_shared(&self) -> bool {
+        self.payload.is_shared()
+    }
+
+    pub fn account(&self) -> Option<&str> {
+        self.payload.account()
+    }
+
+    /// SHA-256 of the serialized payload, used to identify equal requests without keeping their content.
+    pub fn digest(&self) -> Option<Vec<u8>> {
+        self.payload.digest()
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct ResponseFrame {
-    payload: ResponseResult,
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseFrame {
+    pub payload: ResponseResult,
+    /// Echo of the request ID.
+    pub request_id: String,
 }
 
 #[derive(Debug, Default)]
@@ -0,0 +1,134 @@
+//! Recorder of agent traffic, and replay of recorded responses.
+//!
+//! Each request and its response are written as a line of JSON, with passwords redacted. In replay mode,
+//! the host answers requests from a record file instead of agents, matching them by the redacted payload.
+//! If a request is recorded more than once, the last response is used.
+
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use super::protocol::{RequestFrame, RequestPayload, ResponseResult};
+
+/// A request and its response.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Record {
+    pub time: DateTime<Local>,
+    pub request_id: String,
+    /// Request payload, with the password redacted.
+    pub request: RequestPayload,
+    pub response: ResponseResult,
+}
+
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    /// Open the record file, new records are appended.
+    pub fn open(path: &str) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            file: Mutex::new(File::from_std(file)),
+        })
+    }
+
+    pub async fn write(&self, request: &RequestFrame, response: &ResponseResult) {
+        let record = Record {
+            time: Local::now(),
+            request_id: request.request_id().to_string(),
+            request: request.payload().redacted(),
+            response: response.clone(),
+        };
+        let mut line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!(
+                    "Could not serialize record of request {}: {}",
+                    record.request_id, e
+                );
+                return;
+            }
+        };
+        line.push('\n');
+
+        let mut file = self.file.lock().await;
+        let result = match file.write_all(line.as_bytes()).await {
+            Ok(()) => file.flush().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            eprintln!("Could not write record of request {}: {}", record.request_id, e);
+        }
+    }
+}
+
+pub struct Replayer {
+    /// Responses indexed by digest of the redacted request payload.
+    responses: HashMap<Vec<u8>, ResponseResult>,
+}
+
+impl Replayer {
+    pub fn load(path: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let mut responses = HashMap::new();
+
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            let record: Record = serde_json::from_str(line)?;
+            if let Some(key) = record.request.digest() {
+                responses.insert(key, record.response);
+            }
+        }
+        Ok(Self { responses })
+    }
+
+    pub fn get(&self, request: &RequestFrame) -> Option<ResponseResult> {
+        let key = request.payload().redacted().digest()?;
+
+        self.responses.get(&key).cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bridge::{ExpenseRequest, ResponsePayload};
+
+    fn expense_request(password: &str) -> RequestFrame {
+        RequestFrame::new(RequestPayload::CardExpense(ExpenseRequest {
+            account: "1810000000".to_string(),
+            password: password.to_string(),
+            page: Some(1),
+            start_time: None,
+            end_time: None,
+        }))
+    }
+
+    #[tokio::test]
+    pub async fn record_and_replay() {
+        let path = std::env::temp_dir().join(format!("kite-record-{}.jsonl", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+
+        let recorder = Recorder::open(path).unwrap();
+        let response = Ok(ResponsePayload::Pong("recorded".to_string()));
+        recorder.write(&expense_request("secret"), &response).await;
+        drop(recorder);
+
+        let content = std::fs::read_to_string(path).unwrap();
+        assert!(!content.contains("secret"));
+
+        let replayer = Replayer::load(path).unwrap();
+        let replayed = replayer.get(&expense_request("another"));
+        assert!(matches!(replayed, Some(Ok(ResponsePayload::Pong(s))) if s == "recorded"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
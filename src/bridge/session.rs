@@ -0,0 +1,158 @@
+//! Portal sessions on agents, so that OA passwords are sent to each agent once instead of in every request.
+//!
+//! Before sending a request carrying a password, the host looks up the session handle of the account on the
+//! selected agent. If there is none, it sends a `PortalAuth` request and keeps the returned handle. The request
+//! is then sent with the handle and without the password. When the agent reports the handle expired, the host
+//! logs in again and resends the request once. Sessions unused for a while are dropped, and so are the least
+//! recently used ones when the store is full.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+
+use super::protocol::RequestFrame;
+
+/// Max sessions kept.
+const MAX_SESSIONS: usize = 10000;
+/// Sessions unused for this long are dropped, as the portal has likely expired them anyway.
+const SESSION_IDLE_TTL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SessionKey {
+    /// Agent sequence, as sessions are kept by each agent.
+    seq: u16,
+    /// SHA-256 of account and password, so that a changed password leads to a new session.
+    credential: Vec<u8>,
+}
+
+impl SessionKey {
+    /// Key of the request on the agent, or None if the request needs no session.
+    pub fn of(seq: u16, request: &RequestFrame) -> Option<Self> {
+        if request.kind() == "PortalAuth" {
+            return None;
+        }
+        let account = request.account()?;
+        let password = request.payload().secret()?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(account.as_bytes());
+        hasher.update([0u8]);
+        hasher.update(password.as_bytes());
+        Some(Self {
+            seq,
+            credential: hasher.finalize().to_vec(),
+        })
+    }
+}
+
+struct Session {
+    handle: String,
+    last_use: Instant,
+}
+
+pub struct SessionStore {
+    handles: Mutex<HashMap<SessionKey, Session>>,
+    capacity: usize,
+    idle_ttl: Duration,
+}
+
+impl Default for SessionStore {
+    fn default() -> Self {
+        Self::new(MAX_SESSIONS, SESSION_IDLE_TTL)
+    }
+}
+
+impl SessionStore {
+    pub fn new(capacity: usize, idle_ttl: Duration) -> Self {
+        Self {
+            handles: Mutex::new(HashMap::new()),
+            capacity,
+            idle_ttl,
+        }
+    }
+
+    pub fn get(&self, key: &SessionKey) -> Option<String> {
+        let now = Instant::now();
+        let mut handles = self.handles.lock().unwrap();
+
+        match handles.get_mut(key) {
+            Some(session) if now.duration_since(session.last_use) < self.idle_ttl => {
+                session.last_use = now;
+                Some(session.handle.clone())
+            }
+            Some(_) => {
+                handles.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn put(&self, key: SessionKey, handle: String) {
+        let now = Instant::now();
+        let mut handles = self.handles.lock().unwrap();
+
+        if handles.len() >= self.capacity && !handles.contains_key(&key) {
+            handles.retain(|_, session| now.duration_since(session.last_use) < self.idle_ttl);
+            if handles.len() >= self.capacity {
+                // Drop the least recently used eighth at once, rather than scanning again on each login.
+                let mut uses: Vec<Instant> = handles.values().map(|session| session.last_use).collect();
+                let count = (uses.len() / 8).max(1);
+                let (_, &mut threshold, _) = uses.select_nth_unstable(count - 1);
+                handles.retain(|_, session| session.last_use > threshold);
+            }
+        }
+        handles.insert(
+            key,
+            Session {
+                handle,
+                last_use: now,
+            },
+        );
+    }
+
+    pub fn remove(&self, key: &SessionKey) {
+        self.handles.lock().unwrap().remove(key);
+    }
+
+    /// Remove sessions of the agent, called when it leaves the pool.
+    pub fn forget_agent(&self, seq: u16) {
+        self.handles.lock().unwrap().retain(|key, _| key.seq != seq);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread::sleep;
+
+    use super::*;
+
+    fn key(account: &str) -> SessionKey {
+        SessionKey {
+            seq: 0,
+            credential: account.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    pub fn evict_sessions() {
+        let store = SessionStore::new(2, Duration::from_millis(100));
+        store.put(key("a"), "session-a".to_string());
+        sleep(Duration::from_millis(1));
+        store.put(key("b"), "session-b".to_string());
+        sleep(Duration::from_millis(1));
+        assert_eq!(store.get(&key("a")).as_deref(), Some("session-a"));
+
+        // The least recently used one is dropped when the store is full.
+        store.put(key("c"), "session-c".to_string());
+        assert_eq!(store.get(&key("b")), None);
+        assert_eq!(store.get(&key("a")).as_deref(), Some("session-a"));
+        assert_eq!(store.get(&key("c")).as_deref(), Some("session-c"));
+
+        // Idle sessions expire.
+        sleep(Duration::from_millis(150));
+        assert_eq!(store.get(&key("a")), None);
+    }
+}
@@ -0,0 +1,205 @@
+//! Strategies to select an agent for each request.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::Deserialize;
+
+/// Load snapshot of an agent, collected before each selection.
+#[derive(Debug, Clone, Copy)]
+pub struct AgentLoad {
+    /// Agent sequence in the pool.
+    pub seq: u16,
+    /// Processed requests' count.
+    pub requests: u32,
+    /// Requests sent but not yet responded.
+    pub in_flight: u32,
+    /// EWMA of response latency in microseconds, 0 if no request has completed.
+    pub latency: u32,
+}
+
+/// Select an agent from candidates, return its sequence.
+pub trait SelectStrategy: Send + Sync {
+    fn select(&self, candidates: &[AgentLoad]) -> Option<u16>;
+}
+
+/// Strategy kind in config.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StrategyKind {
+    Random,
+    RoundRobin,
+    LeastInFlight,
+    Latency,
+}
+
+impl Default for StrategyKind {
+    fn default() -> Self {
+        StrategyKind::Random
+    }
+}
+
+impl StrategyKind {
+    pub fn build(self) -> Box<dyn SelectStrategy> {
+        match self {
+            StrategyKind::Random => Box::new(Random),
+            StrategyKind::RoundRobin => Box::new(RoundRobin::default()),
+            StrategyKind::LeastInFlight => Box::new(LeastInFlight),
+            StrategyKind::Latency => Box::new(LatencyWeighted),
+        }
+    }
+}
+
+/// Choose an agent randomly.
+pub struct Random;
+
+impl SelectStrategy for Random {
+    fn select(&self, candidates: &[AgentLoad]) -> Option<u16> {
+        use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+        let mut rng = StdRng::from_entropy();
+        candidates.choose(&mut rng).map(|x| x.seq)
+    }
+}
+
+/// Choose agents in turn, ordered by their sequence.
+#[derive(Default)]
+pub struct RoundRobin {
+    cursor: AtomicUsize,
+}
+
+impl SelectStrategy for RoundRobin {
+    fn select(&self, candidates: &[AgentLoad]) -> Option<u16> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let mut seq_list: Vec<u16> = candidates.iter().map(|x| x.seq).collect();
+        seq_list.sort_unstable();
+
+        let cursor = self.cursor.fetch_add(1, Ordering::Relaxed);
+        Some(seq_list[cursor % seq_list.len()])
+    }
+}
+
+/// Choose the agent with the fewest in-flight requests, and the fewest processed requests on tie.
+pub struct LeastInFlight;
+
+impl SelectStrategy for LeastInFlight {
+    fn select(&self, candidates: &[AgentLoad]) -> Option<u16> {
+        candidates
+            .iter()
+            .min_by_key(|x| (x.in_flight, x.requests))
+            .map(|x| x.seq)
+    }
+}
+
+/// Choose the agent with the lowest latency EWMA, weighted by in-flight requests.
+/// Agents without any latency sample are scored by the mean of the measured ones, so that a new agent gets
+/// its share of requests without taking all of them before its first response.
+pub struct LatencyWeighted;
+
+impl SelectStrategy for LatencyWeighted {
+    fn select(&self, candidates: &[AgentLoad]) -> Option<u16> {
+        let measured: Vec<u64> = candidates
+            .iter()
+            .filter(|x| x.latency != 0)
+            .map(|x| x.latency as u64)
+            .collect();
+        let mean = if measured.is_empty() {
+            0
+        } else {
+            measured.iter().sum::<u64>() / measured.len() as u64
+        };
+
+        candidates
+            .iter()
+            .min_by_key(|x| {
+                let latency = if x.latency == 0 { mean } else { x.latency as u64 };
+                (latency * (x.in_flight as u64 + 1), x.in_flight)
+            })
+            .map(|x| x.seq)
+    }
+}
+
+/// Select an agent for the key by rendezvous (highest random weight) hashing over `(seq, name)` pairs.
+///
+/// Each agent scores `hash(key, name)` and the highest one wins, so a key keeps its agent as long as that one
+/// is available. When an agent leaves, only its keys move to others, and a new agent only takes over keys
+/// it scores highest on. Names are used instead of sequences, so that a reconnected agent gets its keys back.
+pub fn rendezvous(key: &str, nodes: &[(u16, &str)]) -> Option<u16> {
+    nodes
+        .iter()
+        .max_by_key(|(seq, name)| {
+            let mut hasher = DefaultHasher::new();
+            (key, name).hash(&mut hasher);
+            // Agents with the same name are ordered by sequence.
+            (hasher.finish(), std::cmp::Reverse(*seq))
+        })
+        .map(|(seq, _)| *seq)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn load(seq: u16, in_flight: u32, latency: u32) -> AgentLoad {
+        AgentLoad {
+            seq,
+            requests: 0,
+            in_flight,
+            latency,
+        }
+    }
+
+    #[test]
+    pub fn round_robin() {
+        let strategy = RoundRobin::default();
+        let candidates = [load(3, 0, 0), load(1, 0, 0)];
+
+        assert_eq!(strategy.select(&candidates), Some(1));
+        assert_eq!(strategy.select(&candidates), Some(3));
+        assert_eq!(strategy.select(&candidates), Some(1));
+        assert_eq!(strategy.select(&[]), None);
+    }
+
+    #[test]
+    pub fn least_in_flight() {
+        let candidates = [load(0, 5, 0), load(1, 2, 0), load(2, 3, 0)];
+
+        assert_eq!(LeastInFlight.select(&candidates), Some(1));
+    }
+
+    #[test]
+    pub fn rendezvous_rebalance() {
+        let nodes = [(0, "a"), (1, "b"), (2, "c")];
+        let keys: Vec<String> = (0..100).map(|i| format!("18100{}", i)).collect();
+        let before: Vec<u16> = keys.iter().map(|k| rendezvous(k, &nodes).unwrap()).collect();
+
+        // Keys stay on their agents unless the agent leaves.
+        let after: Vec<u16> = keys.iter().map(|k| rendezvous(k, &nodes[..2]).unwrap()).collect();
+        for (b, a) in before.iter().zip(after.iter()) {
+            if *b != 2 {
+                assert_eq!(a, b);
+            }
+        }
+        assert_eq!(rendezvous("1810000", &[]), None);
+    }
+
+    #[test]
+    pub fn latency_weighted() {
+        // 100 * 4 > 300 * 1
+        let candidates = [load(0, 3, 100), load(1, 0, 300)];
+        assert_eq!(LatencyWeighted.select(&candidates), Some(1));
+
+        // Unmeasured agent is scored by the mean latency: 200 * 2 > 100 * 1, and 200 * 1 < 100 * 3.
+        let candidates = [load(0, 0, 100), load(1, 0, 300), load(2, 1, 0)];
+        assert_eq!(LatencyWeighted.select(&candidates), Some(0));
+        let candidates = [load(0, 2, 100), load(1, 0, 300), load(2, 0, 0)];
+        assert_eq!(LatencyWeighted.select(&candidates), Some(2));
+
+        // Without any measured agent, the one with fewer in-flight requests wins.
+        let candidates = [load(0, 2, 0), load(1, 1, 0)];
+        assert_eq!(LatencyWeighted.select(&candidates), Some(1));
+    }
+}
@@ -0,0 +1,57 @@
+//! TLS support for the agent listener.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, Certificate, NoClientAuth, PrivateKey, RootCertStore, ServerConfig,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::HostTlsConfig;
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    certs(&mut reader).map_err(|_| anyhow!("Invalid certificate file: {}", path))
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKey> {
+    // Try PKCS#8 first, and then the RSA format.
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut keys = pkcs8_private_keys(&mut reader).unwrap_or_default();
+    if keys.is_empty() {
+        let mut reader = BufReader::new(File::open(path)?);
+        keys = rsa_private_keys(&mut reader).unwrap_or_default();
+    }
+    keys.pop()
+        .ok_or_else(|| anyhow!("No private key found in {}", path))
+}
+
+fn load_client_roots(path: &str) -> Result<RootCertStore> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut roots = RootCertStore::empty();
+
+    let (valid, _) = roots
+        .add_pem_file(&mut reader)
+        .map_err(|_| anyhow!("Invalid CA file: {}", path))?;
+    if valid == 0 {
+        return Err(anyhow!("No CA certificate found in {}", path));
+    }
+    Ok(roots)
+}
+
+/// Build a TLS acceptor from config. Agents must present a certificate signed by `client_ca` if it is set.
+pub fn load_tls_acceptor(config: &HostTlsConfig) -> Result<TlsAcceptor> {
+    let verifier = match &config.client_ca {
+        Some(ca_path) => AllowAnyAuthenticatedClient::new(load_client_roots(ca_path)?),
+        None => NoClientAuth::new(),
+    };
+    let mut server_config = ServerConfig::new(verifier);
+    server_config.set_single_cert(load_certs(&config.cert)?, load_private_key(&config.key)?)?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
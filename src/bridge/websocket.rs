@@ -0,0 +1,135 @@
+//! WebSocket transport, for agents which can only reach the host through HTTP(S) proxies.
+//!
+//! The same bincode frames are carried in binary WebSocket messages. Since the frames are length-prefixed,
+//! message boundaries do not matter, and the data is pumped through an in-memory pipe which the host
+//! treats like a TCP stream. Pings from the agent, or proxies in between, are answered with pongs.
+
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{Codec, Frame, Item, Message};
+use actix_web::error::PayloadError;
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+use super::host::AgentManager;
+
+/// Buffer size of the pipe, and also the max size of outgoing messages.
+const PIPE_SIZE: usize = 64 * 1024;
+/// Max size of incoming frames. Some responses are about 1M.
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Decode WebSocket frames from the request payload, and write binary data to the pipe.
+/// Payloads of pings are sent to `pings`, to be answered in the outgoing messages.
+async fn pump_incoming<S, W>(mut incoming: S, mut writer: W, pings: mpsc::UnboundedSender<Bytes>)
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut codec = Codec::new().max_size(MAX_FRAME_SIZE);
+    let mut buffer = BytesMut::new();
+
+    while let Some(Ok(chunk)) = incoming.next().await {
+        buffer.extend_from_slice(&chunk);
+        loop {
+            let data = match codec.decode(&mut buffer) {
+                Ok(Some(Frame::Binary(data)))
+                | Ok(Some(Frame::Continuation(Item::FirstBinary(data))))
+                | Ok(Some(Frame::Continuation(Item::Continue(data))))
+                | Ok(Some(Frame::Continuation(Item::Last(data)))) => data,
+                Ok(Some(Frame::Ping(data))) => {
+                    let _ = pings.send(data);
+                    continue;
+                }
+                // Other control frames are ignored.
+                Ok(Some(Frame::Close(_))) | Err(_) => return,
+                Ok(Some(_)) => continue,
+                Ok(None) => break,
+            };
+            if writer.write_all(&data).await.is_err() {
+                return;
+            }
+        }
+    }
+    // The writer is dropped here, and the host sees the end of stream.
+}
+
+/// Read data from the pipe, and encode it as binary WebSocket messages, with pongs to the pings received.
+fn outgoing_messages<R>(
+    reader: R,
+    pings: mpsc::UnboundedReceiver<Bytes>,
+) -> impl Stream<Item = std::io::Result<Bytes>> + Unpin
+where
+    R: AsyncRead + Unpin,
+{
+    let state = (reader, pings, Codec::new());
+    let messages = futures::stream::unfold(state, |(mut reader, mut pings, mut codec)| async move {
+        let mut data = vec![0u8; PIPE_SIZE];
+        let message = tokio::select! {
+            read = reader.read(&mut data) => match read {
+                Ok(0) | Err(_) => return None,
+                Ok(size) => {
+                    data.truncate(size);
+                    Message::Binary(Bytes::from(data))
+                }
+            },
+            Some(ping) = pings.recv() => Message::Pong(ping),
+        };
+
+        let mut encoded = BytesMut::new();
+        codec.encode(message, &mut encoded).ok()?;
+        Some((Ok(encoded.freeze()), (reader, pings, codec)))
+    });
+    Box::pin(messages)
+}
+
+/// Add the agent connected over WebSocket to the pool of the manager.
+/// Return the outgoing WebSocket data, which should be sent as the body of the handshake response.
+pub fn accept<S>(
+    manager: &AgentManager,
+    incoming: S,
+    source_addr: String,
+) -> impl Stream<Item = std::io::Result<Bytes>> + Unpin
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin + 'static,
+{
+    let (agent_side, host_side) = tokio::io::duplex(PIPE_SIZE);
+    let (reader, writer) = tokio::io::split(agent_side);
+    let (ping_tx, ping_rx) = mpsc::unbounded_channel();
+
+    // Request payload is bound to the current thread.
+    actix_web::rt::spawn(pump_incoming(incoming, writer, ping_tx));
+
+    let manager = manager.clone();
+    tokio::spawn(async move {
+        if let Err(e) = manager.join(Box::new(host_side), source_addr.clone()).await {
+            eprintln!("Agent {} (WebSocket) rejected: {}", source_addr, e);
+        }
+    });
+    outgoing_messages(reader, ping_rx)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn answer_ping() {
+        let (agent_side, _host_side) = tokio::io::duplex(PIPE_SIZE);
+        let (reader, writer) = tokio::io::split(agent_side);
+        let (ping_tx, ping_rx) = mpsc::unbounded_channel();
+
+        let mut client_codec = Codec::new().client_mode();
+        let mut ping = BytesMut::new();
+        client_codec
+            .encode(Message::Ping(Bytes::from_static(b"kite")), &mut ping)
+            .unwrap();
+        let incoming = futures::stream::iter(vec![Ok(ping.freeze())]);
+        tokio::spawn(pump_incoming(incoming, writer, ping_tx));
+
+        let mut outgoing = outgoing_messages(reader, ping_rx);
+        let mut message = BytesMut::from(&outgoing.next().await.unwrap().unwrap()[..]);
+        let frame = client_codec.decode(&mut message).unwrap();
+        assert_eq!(frame, Some(Frame::Pong(Bytes::from_static(b"kite"))));
+    }
+}
@@ -1,7 +1,12 @@
+use std::collections::HashMap;
 use std::fs;
+use std::num::NonZeroU64;
 
+use chrono::{DateTime, Local};
 use serde::Deserialize;
 
+use crate::bridge::StrategyKind;
+
 // Look and rename kite.example.toml
 const DEFAULT_CONFIG_PATH: &str = "kite.toml";
 
@@ -13,6 +18,8 @@ pub struct Config {
     pub wechat: WechatConfig,
     /// Host config. Used to config the communication with agents.
     pub host: HostConfig,
+    /// Keys for encrypting secrets stored in database.
+    pub crypto: CryptoConfig,
 }
 
 #[derive(Deserialize)]
@@ -26,6 +33,47 @@ pub struct ServerConfig {
     pub db: String,
     /// Attachment directory.
     pub attachment: String,
+    /// Lifetime of access tokens in seconds.
+    #[serde(default = "default_access_token_ttl")]
+    pub access_token_ttl: i64,
+    /// Lifetime of refresh tokens in seconds.
+    #[serde(default = "default_refresh_token_ttl")]
+    pub refresh_token_ttl: i64,
+    /// Tokens issued without an expiration time are accepted until this time, like "2021-12-01T00:00:00+08:00".
+    /// They are rejected if not set.
+    pub legacy_token_deadline: Option<DateTime<Local>>,
+    /// Route access rules, matched in order before the built-in ones.
+    #[serde(default)]
+    pub access: Vec<AccessRuleConfig>,
+    /// Rate limits by route group. A request is counted in the first matching group only.
+    #[serde(default)]
+    pub rate_limit: Vec<RateLimitConfig>,
+    /// Reverse proxies in front of the server, each appending the address it got the request from to
+    /// "X-Forwarded-For". The header is ignored if it is 0.
+    #[serde(default)]
+    pub trusted_proxies: usize,
+}
+
+#[derive(Deserialize)]
+pub struct AccessRuleConfig {
+    /// Path pattern, like "/api/v1/mall/goods/{item_code}".
+    pub path: String,
+    /// HTTP method like "GET", or any method if not set.
+    pub method: Option<String>,
+    /// "public", "authenticated" or "role:<name>".
+    pub access: String,
+}
+
+#[derive(Deserialize)]
+pub struct RateLimitConfig {
+    /// Path pattern of the group, like "/api/v1/library{tail}*".
+    pub path: String,
+    /// HTTP method like "POST", or any method if not set.
+    pub method: Option<String>,
+    /// Max requests in a burst, for each user or client IP.
+    pub capacity: f64,
+    /// Requests allowed per second in the long run.
+    pub rate: f64,
 }
 
 #[derive(Deserialize)]
@@ -43,14 +91,123 @@ pub struct HostConfig {
     pub bind: String,
     /// Max agent count.
     pub max: u8,
+    /// Agent selection strategy: "random", "round-robin", "least-in-flight" or "latency".
+    #[serde(default)]
+    pub strategy: StrategyKind,
+    /// Send requests of the same account to the same agent, so that agents can reuse portal sessions.
+    /// Other requests are still distributed by `strategy`.
+    #[serde(default)]
+    pub affinity: bool,
+    /// Max times to retry an idempotent request on other agents when the transport fails or the agent times out.
+    #[serde(default = "default_retry")]
+    pub retry: u8,
+    /// Heartbeat interval in seconds, also the time limit of each heartbeat. Zero is rejected.
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: NonZeroU64,
+    /// Agents missing this many heartbeats in a row are removed.
+    #[serde(default = "default_max_missed_heartbeat")]
+    pub max_missed_heartbeat: u8,
+    /// Max requests in flight on each agent.
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: u32,
+    /// Max background requests in flight on each agent, like those of the activity daemon and expense back-fill.
+    #[serde(default = "default_background_in_flight")]
+    pub background_in_flight: u32,
+    /// In-flight slots on each agent reserved for interactive requests. Background requests are admitted only
+    /// while fewer than `max_in_flight - reserved_in_flight` requests are in flight.
+    #[serde(default = "default_reserved_in_flight")]
+    pub reserved_in_flight: u32,
+    /// Default timeout of agent requests in seconds.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Timeouts in seconds by request kind, such as "Ping" or "CardExpense", overriding the default one.
+    #[serde(default)]
+    pub timeouts: HashMap<String, u64>,
+    /// Response cache TTLs in seconds by request kind. Kinds not listed are not cached.
+    #[serde(default)]
+    pub cache_ttl: HashMap<String, u64>,
+    /// Pre-shared key for the agent handshake, used when the agent has no key of its own.
+    pub secret: Option<String>,
+    /// Per-agent keys, indexed by agent name.
+    #[serde(default)]
+    pub agents: HashMap<String, String>,
+    /// Whether agents may connect over the WebSocket endpoint, for those behind HTTP-only proxies.
+    #[serde(default)]
+    pub websocket: bool,
+    /// TLS settings. Agents connect over plain TCP if not set.
+    pub tls: Option<HostTlsConfig>,
+    /// File to record agent traffic in, as JSON lines with passwords redacted.
+    pub record: Option<String>,
+    /// Record file to answer requests from, instead of agents. Used to reproduce problems offline.
+    pub replay: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct HostTlsConfig {
+    /// Certificate chain file in PEM format.
+    pub cert: String,
+    /// Private key file in PEM format, PKCS#8 or RSA.
+    pub key: String,
+    /// CA file for verifying agent certificates. Client certificates are not required if not set.
+    pub client_ca: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CryptoConfig {
+    /// ID of the master key to encrypt new secrets with.
+    pub current: String,
+    /// Master keys by ID, each 32 bytes encoded in base64. Retired keys are kept to decrypt old secrets until
+    /// they are re-encrypted by `kite-server encrypt-secrets`.
+    pub keys: HashMap<String, String>,
+}
+
+fn default_access_token_ttl() -> i64 {
+    2 * 3600
+}
+
+fn default_refresh_token_ttl() -> i64 {
+    30 * 86400
+}
+
+fn default_retry() -> u8 {
+    2
+}
+
+fn default_heartbeat_interval() -> NonZeroU64 {
+    NonZeroU64::new(30).unwrap()
+}
+
+fn default_max_missed_heartbeat() -> u8 {
+    3
+}
+
+fn default_max_in_flight() -> u32 {
+    64
+}
+
+fn default_background_in_flight() -> u32 {
+    4
+}
+
+fn default_reserved_in_flight() -> u32 {
+    8
+}
+
+fn default_timeout() -> u64 {
+    30
 }
 
+/// Environment variable naming another config file to load instead of DEFAULT_CONFIG_PATH.
+pub const CONFIG_PATH_ENV: &str = "KITE_CONFIG";
+
 lazy_static! {
-    pub static ref CONFIG: Config = load_config(DEFAULT_CONFIG_PATH)
-        .unwrap_or_else(|e| { panic!("Failed to parse {}: {}", DEFAULT_CONFIG_PATH, e) });
+    pub static ref CONFIG: Config = {
+        let path = std::env::var(CONFIG_PATH_ENV).unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+        load_config(&path).unwrap_or_else(|e| panic!("Failed to parse {}: {}", path, e))
+    };
 }
 
-/// Load the global configuration from DEFAULT_CONFIG_PATH on the startup.
+/// Load the global configuration on the startup.
 fn load_config(config_path: &str) -> Result<Config, anyhow::Error> {
     let config_content = fs::read_to_string(config_path)?;
     let config = toml::from_str(config_content.as_str())?;
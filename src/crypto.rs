@@ -0,0 +1,228 @@
+//! Envelope encryption of secrets stored in database, like OA passwords.
+//!
+//! Each secret is encrypted by AES-256-GCM with a random data key, and the data key is encrypted with a master
+//! key from the `[crypto]` config. Stored values look like `v1:<key id>:<wrapped data key>:<ciphertext>`, where
+//! the last two parts are base64 of a 12-byte nonce followed by the AES-GCM output. Rotating the master key only
+//! re-encrypts the data keys, and old master keys are kept in config until all rows are rewrapped.
+//! Values which are not in this format under a known key are legacy plaintext, and they are returned as is.
+
+use std::collections::HashMap;
+
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::{anyhow, bail, Result};
+use rand::Rng;
+
+use crate::config::{CryptoConfig, CONFIG};
+
+const PREFIX: &str = "v1";
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+
+lazy_static! {
+    pub static ref KEYRING: Keyring =
+        Keyring::new(&CONFIG.crypto).unwrap_or_else(|e| panic!("Invalid crypto config: {}", e));
+}
+
+/// Master keys by ID, and the one to encrypt new secrets with.
+pub struct Keyring {
+    current: String,
+    keys: HashMap<String, Aes256Gcm>,
+}
+
+/// Encrypt with a random nonce, and put the nonce before the ciphertext.
+fn seal(cipher: &Aes256Gcm, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let nonce: [u8; NONCE_LENGTH] = rand::thread_rng().gen();
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| anyhow!("Failed to encrypt"))?;
+
+    Ok([&nonce[..], &ciphertext].concat())
+}
+
+fn open(cipher: &Aes256Gcm, sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < NONCE_LENGTH {
+        bail!("Ciphertext is too short");
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Failed to decrypt, the key or ciphertext is wrong"))
+}
+
+/// Parts of an encrypted value.
+struct Envelope {
+    key_id: String,
+    wrapped_key: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+impl Envelope {
+    fn parse(stored: &str) -> Result<Self> {
+        let parts: Vec<&str> = stored.split(':').collect();
+        if parts.len() != 4 || parts[0] != PREFIX {
+            bail!("Malformed encrypted value");
+        }
+        Ok(Self {
+            key_id: parts[1].to_string(),
+            wrapped_key: base64::decode(parts[2])?,
+            ciphertext: base64::decode(parts[3])?,
+        })
+    }
+
+    fn encode(&self) -> String {
+        format!(
+            "{}:{}:{}:{}",
+            PREFIX,
+            self.key_id,
+            base64::encode(&self.wrapped_key),
+            base64::encode(&self.ciphertext)
+        )
+    }
+}
+
+impl Keyring {
+    pub fn new(config: &CryptoConfig) -> Result<Self> {
+        let mut keys = HashMap::new();
+
+        for (id, key) in &config.keys {
+            if id.is_empty() || id.contains(':') {
+                bail!("Invalid key id \"{}\"", id);
+            }
+            let key = base64::decode(key)?;
+            if key.len() != KEY_LENGTH {
+                bail!("Key \"{}\" should be {} bytes", id, KEY_LENGTH);
+            }
+            keys.insert(id.clone(), Aes256Gcm::new(Key::from_slice(&key)));
+        }
+        if !keys.contains_key(&config.current) {
+            bail!("Current key \"{}\" is not found", config.current);
+        }
+        Ok(Self {
+            current: config.current.clone(),
+            keys,
+        })
+    }
+
+    fn key(&self, id: &str) -> Result<&Aes256Gcm> {
+        self.keys
+            .get(id)
+            .ok_or_else(|| anyhow!("Key \"{}\" is not found", id))
+    }
+
+    /// Encrypt the secret with a new data key under the current master key.
+    pub fn encrypt(&self, plaintext: &str) -> Result<String> {
+        let data_key: [u8; KEY_LENGTH] = rand::thread_rng().gen();
+        let cipher = Aes256Gcm::new(Key::from_slice(&data_key));
+
+        let envelope = Envelope {
+            key_id: self.current.clone(),
+            wrapped_key: seal(self.key(&self.current)?, &data_key)?,
+            ciphertext: seal(&cipher, plaintext.as_bytes())?,
+        };
+        Ok(envelope.encode())
+    }
+
+    /// Whether the stored value is encrypted under a known master key, rather than a legacy plaintext which
+    /// may happen to start with the prefix.
+    pub fn is_encrypted(&self, stored: &str) -> bool {
+        matches!(self.parse(stored), Ok(Some(_)))
+    }
+
+    /// Parse the stored value, or return None for legacy plaintext. A well-formed value under an unknown key is
+    /// an error rather than plaintext, so that it is neither sent as a password nor encrypted again.
+    fn parse(&self, stored: &str) -> Result<Option<Envelope>> {
+        let envelope = match Envelope::parse(stored) {
+            Ok(envelope) => envelope,
+            Err(_) => return Ok(None),
+        };
+        self.key(&envelope.key_id)?;
+        Ok(Some(envelope))
+    }
+
+    /// Decrypt the stored value. Legacy plaintext is returned as is.
+    pub fn decrypt(&self, stored: &str) -> Result<String> {
+        let envelope = match self.parse(stored)? {
+            Some(envelope) => envelope,
+            None => return Ok(stored.to_string()),
+        };
+        let data_key = open(self.key(&envelope.key_id)?, &envelope.wrapped_key)?;
+        let cipher = Aes256Gcm::new(Key::from_slice(&data_key));
+
+        let plaintext = open(&cipher, &envelope.ciphertext)?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+
+    /// Encrypt legacy plaintext, or re-encrypt the data key under the current master key.
+    /// Return None if the value is already under the current key.
+    pub fn rewrap(&self, stored: &str) -> Result<Option<String>> {
+        let mut envelope = match self.parse(stored)? {
+            Some(envelope) => envelope,
+            None => return self.encrypt(stored).map(Some),
+        };
+        if envelope.key_id == self.current {
+            return Ok(None);
+        }
+        let data_key = open(self.key(&envelope.key_id)?, &envelope.wrapped_key)?;
+
+        envelope.key_id = self.current.clone();
+        envelope.wrapped_key = seal(self.key(&self.current)?, &data_key)?;
+        Ok(Some(envelope.encode()))
+    }
+}
+
+/// Encrypt the secret under the current master key in config.
+pub fn encrypt(plaintext: &str) -> Result<String> {
+    KEYRING.encrypt(plaintext)
+}
+
+/// Decrypt the secret stored in database. Call it only where the plaintext is needed, like building an agent
+/// request, and do not keep the result around.
+pub fn decrypt(stored: &str) -> Result<String> {
+    KEYRING.decrypt(stored)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn encrypt_and_rotate() {
+        let mut config = CryptoConfig {
+            current: "2021a".to_string(),
+            keys: HashMap::new(),
+        };
+        config.keys.insert("2021a".to_string(), base64::encode([1u8; 32]));
+        let old = Keyring::new(&config).unwrap();
+
+        let stored = old.encrypt("oa-password").unwrap();
+        assert!(old.is_encrypted(&stored));
+        assert!(!stored.contains("oa-password"));
+        assert_eq!(old.decrypt(&stored).unwrap(), "oa-password");
+        assert_eq!(old.decrypt("plaintext").unwrap(), "plaintext");
+        // Plaintext starting with the prefix is not mistaken for encrypted values.
+        for plaintext in ["v1:", "v1:2021a", "v1:2021a:abc", "v1:2021a:!:!", "v1:a:b:c:d"] {
+            assert!(!old.is_encrypted(plaintext));
+            assert_eq!(old.decrypt(plaintext).unwrap(), plaintext);
+        }
+        assert_eq!(old.rewrap(&stored).unwrap(), None);
+
+        config.current = "2021b".to_string();
+        config.keys.insert("2021b".to_string(), base64::encode([2u8; 32]));
+        let new = Keyring::new(&config).unwrap();
+
+        let rewrapped = new.rewrap(&stored).unwrap().unwrap();
+        assert!(rewrapped.starts_with("v1:2021b:"));
+        assert_eq!(new.decrypt(&rewrapped).unwrap(), "oa-password");
+        assert!(!old.is_encrypted(&rewrapped));
+        assert!(old.decrypt(&rewrapped).is_err());
+        assert!(old.rewrap(&rewrapped).is_err());
+
+        // Tampered values are rejected.
+        let mut tampered = rewrapped.into_bytes();
+        let last = tampered.len() - 2;
+        tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+        assert!(new.decrypt(&String::from_utf8(tampered).unwrap()).is_err());
+    }
+}
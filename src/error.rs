@@ -11,6 +11,7 @@ use sqlx::error::Error as SqlError;
 use wechat_sdk::WxClientError;
 
 use crate::bridge::ErrorResponse as AgentError;
+use crate::request_id;
 
 pub type Result<T> = std::result::Result<T, ApiError>;
 pub type Error = ApiError;
@@ -47,12 +48,24 @@ impl ResponseError for ApiError {
     fn status_code(&self) -> StatusCode {
         StatusCode::OK
     }
-    // Make json response body for error.
+    // Make json response body for error, with the request ID for tracing.
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::Ok().json(&self)
+        HttpResponse::Ok().json(&ErrorBody {
+            error: self,
+            request_id: request_id::current(),
+        })
     }
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ErrorBody<'a> {
+    #[serde(flatten)]
+    error: &'a ApiError,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
 impl ApiError {
     pub fn new<T: ToPrimitive + std::error::Error>(sub_err: T) -> Self {
         Self {
@@ -1,5 +1,7 @@
+use chrono::Local;
+use jsonwebtoken::errors::ErrorKind;
 use serde::de::DeserializeOwned;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::config::CONFIG;
 use crate::error::Result;
@@ -15,24 +17,42 @@ pub fn encode_jwt<T: Serialize>(claims: &T) -> Result<String> {
     )?)
 }
 
+/// Claims with the optional expiration time, used to find tokens issued before expiration was introduced.
+#[derive(Deserialize)]
+struct LegacyClaims<T> {
+    exp: Option<u64>,
+    #[serde(flatten)]
+    claims: T,
+}
+
+/// Whether tokens without an expiration time are still accepted.
+fn accept_legacy() -> bool {
+    matches!(CONFIG.server.legacy_token_deadline, Some(deadline) if Local::now() < deadline)
+}
+
 pub fn decode_jwt<T: DeserializeOwned>(token: &str) -> Option<T> {
     let key = &CONFIG.server.secret.as_ref();
     let decoding_key = jsonwebtoken::DecodingKey::from_secret(key);
-    let option = jsonwebtoken::Validation {
-        validate_exp: false,
-        ..jsonwebtoken::Validation::default()
-    };
-    let t = jsonwebtoken::decode::<T>(token, &decoding_key, &option);
+    let t = jsonwebtoken::decode::<T>(token, &decoding_key, &jsonwebtoken::Validation::default());
 
-    if let Ok(token_data) = t {
-        Some(token_data.claims)
-    } else {
-        None
-    }
-}
+    match t {
+        Ok(token_data) => Some(token_data.claims),
+        // A missing exp claim is also reported as expired signature.
+        Err(e) if matches!(e.kind(), ErrorKind::ExpiredSignature) && accept_legacy() => {
+            let option = jsonwebtoken::Validation {
+                validate_exp: false,
+                ..jsonwebtoken::Validation::default()
+            };
+            let token_data =
+                jsonwebtoken::decode::<LegacyClaims<T>>(token, &decoding_key, &option).ok()?;
 
-pub fn validate_jwt<T: DeserializeOwned>(token: &str) -> bool {
-    decode_jwt::<T>(token).is_some()
+            match token_data.claims {
+                LegacyClaims { exp: None, claims } => Some(claims),
+                _ => None,
+            }
+        }
+        Err(_) => None,
+    }
 }
 
 // #[cfg(test)]
@@ -10,18 +10,29 @@ extern crate num_derive;
 use futures::TryFutureExt;
 
 // Import main function.
-use crate::services::server_main;
+use crate::services::{encrypt_secrets_main, server_main};
 
 mod bridge;
 mod config;
+mod crypto;
 mod error;
 mod ipset;
 mod jwt;
 mod models;
+mod request_id;
 mod services;
 
 #[actix_web::main]
 async fn main() {
+    // Run `kite-server encrypt-secrets` once after deploying encryption of OA secrets, and after rotating keys.
+    if std::env::args().nth(1).as_deref() == Some("encrypt-secrets") {
+        if let Err(e) = encrypt_secrets_main().await {
+            // Exit with an error, so that deploy scripts stop before old keys are removed.
+            eprintln!("Failed to encrypt OA secrets: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
     server_main()
         .unwrap_or_else(|e| {
             println!("Failed to run server_main(): {}", e);
@@ -6,6 +6,8 @@ use thiserror::Error;
 
 use crate::error::ApiError;
 
+/// Audit log of privileged actions.
+pub mod audit;
 /// Telephone mod
 pub mod contact;
 /// Course and score management.
@@ -50,6 +52,8 @@ pub enum CommonError {
     Forbidden = 5,
     #[error("需要实名认证后才能继续")]
     IdentityNeeded = 6,
+    #[error("请求过于频繁")]
+    TooManyRequests = 7,
 }
 
 impl From<CommonError> for ApiError {
@@ -94,8 +98,8 @@ impl PageView {
         }
         DEFAULT_ITEM_COUNT
     }
-    /// Calculate offset
+    /// Calculate offset. The first page is used if the index is not set.
     pub fn offset(&self, max_count: u16) -> u16 {
-        self.count(max_count) * (self.index() - 1)
+        self.count(max_count) * self.index().saturating_sub(1)
     }
 }
@@ -0,0 +1,133 @@
+//! Audit log of privileged actions.
+//!
+//! Each time a user acts with a permission rather than as the owner, like an administrator editing another
+//! user, a record of who did what to which target is appended to table "audit_log". Records can not be
+//! updated or deleted, which is enforced by a trigger on the table.
+//!
+//! Records of changes are written in the same transaction as the changes, so that neither is kept without the
+//! other.
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::{Executor, PgPool, Postgres};
+
+use crate::error::Result;
+use crate::models::PageView;
+use crate::request_id;
+
+#[derive(Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditRecord {
+    pub id: i64,
+    pub ts: NaiveDateTime,
+    /// Uid of the user who did the action.
+    pub actor: i32,
+    /// Action name, like "user.update".
+    pub action: String,
+    /// Target of the action, like "user:10".
+    pub target: String,
+    /// Request ID, to find the request in logs.
+    pub request_id: Option<String>,
+    /// Changed fields as {"field": [old, new]}, or other details of the action.
+    pub diff: Option<Value>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditFilter {
+    pub actor: Option<i32>,
+    /// Action name, or its prefix before a dot like "user".
+    pub action: Option<String>,
+    pub target: Option<String>,
+    /// Start time, like "2021-11-01T00:00:00".
+    pub from: Option<NaiveDateTime>,
+    /// End time, excluded.
+    pub to: Option<NaiveDateTime>,
+}
+
+/// Append a record of the action done in the current request. Pass the transaction of the change, if any.
+pub async fn record<'c, E>(
+    client: E,
+    actor: i32,
+    action: &str,
+    target: &str,
+    diff: Option<Value>,
+) -> Result<()>
+where
+    E: Executor<'c, Database = Postgres>,
+{
+    sqlx::query(
+        "INSERT INTO audit_log (actor, action, target, request_id, diff)
+            VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(actor)
+    .bind(action)
+    .bind(target)
+    .bind(request_id::current())
+    .bind(diff)
+    .execute(client)
+    .await?;
+    Ok(())
+}
+
+/// Query records matching the filter, latest first.
+pub async fn query(client: &PgPool, filter: &AuditFilter, page: &PageView) -> Result<Vec<AuditRecord>> {
+    let records: Vec<AuditRecord> = sqlx::query_as(
+        "SELECT id, ts, actor, action, target, request_id, diff FROM audit_log
+            WHERE ($1::integer IS NULL OR actor = $1)
+                AND ($2::text IS NULL OR action = $2 OR action LIKE $2 || '.%')
+                AND ($3::text IS NULL OR target = $3)
+                AND ($4::timestamp IS NULL OR ts >= $4)
+                AND ($5::timestamp IS NULL OR ts < $5)
+            ORDER BY id DESC OFFSET $6 LIMIT $7",
+    )
+    .bind(filter.actor)
+    .bind(&filter.action)
+    .bind(&filter.target)
+    .bind(filter.from)
+    .bind(filter.to)
+    .bind(page.offset(100) as i64)
+    .bind(page.count(100) as i64)
+    .fetch_all(client)
+    .await?;
+    Ok(records)
+}
+
+/// Fields of JSON objects changed from `before` to `after`, as {"field": [old, new]}.
+pub fn diff(before: &Value, after: &Value) -> Value {
+    let empty = Map::new();
+    let before = before.as_object().unwrap_or(&empty);
+    let after = after.as_object().unwrap_or(&empty);
+
+    let mut changes = Map::new();
+    for (key, new) in after {
+        let old = before.get(key).unwrap_or(&Value::Null);
+        if old != new {
+            changes.insert(key.clone(), Value::Array(vec![old.clone(), new.clone()]));
+        }
+    }
+    for (key, old) in before {
+        if !after.contains_key(key) {
+            changes.insert(key.clone(), Value::Array(vec![old.clone(), Value::Null]));
+        }
+    }
+    Value::Object(changes)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    #[test]
+    pub fn diff() {
+        let before = json!({"nickName": "kite", "city": null, "gender": 1});
+        let after = json!({"nickName": "sit", "city": "Shanghai", "gender": 1});
+
+        assert_eq!(
+            super::diff(&before, &after),
+            json!({"nickName": ["kite", "sit"], "city": [null, "Shanghai"]})
+        );
+        assert_eq!(super::diff(&after, &after), json!({}));
+    }
+}
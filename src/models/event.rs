@@ -1,7 +1,7 @@
 //! This module provides the ability to create, update and delete events, records and other about signs.
 use chrono::{DateTime, Local, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sqlx::{Executor, PgPool, Postgres};
 use uuid::Uuid;
 
 use crate::error::Result;
@@ -161,6 +161,29 @@ impl Event {
     }
 
     pub async fn get_event_detail(_source: i32) {}
+
+    /// Publisher of the event published in kite, or None if there is no such event.
+    pub async fn get_publisher(client: &PgPool, event_id: i32) -> Result<Option<i32>> {
+        let publisher: Option<(i32,)> = sqlx::query_as(
+            "SELECT publisher_uid FROM events.events WHERE event_id = $1 AND NOT deleted",
+        )
+        .bind(event_id)
+        .fetch_optional(client)
+        .await?;
+        Ok(publisher.map(|(uid,)| uid))
+    }
+
+    /// Mark the event deleted, so that it is no longer listed.
+    pub async fn delete<'c, E>(client: E, event_id: i32) -> Result<()>
+    where
+        E: Executor<'c, Database = Postgres>,
+    {
+        sqlx::query("UPDATE events.events SET deleted = true WHERE event_id = $1")
+            .bind(event_id)
+            .execute(client)
+            .await?;
+        Ok(())
+    }
 }
 
 impl Event {
@@ -75,6 +75,26 @@ impl<'a> FreshmanManager<'a> {
         }
     }
 
+    /// Query student basic without the secret, for operators helping freshmen.
+    ///
+    /// Query string can be student id or ticket number, but not name, for names are not unique without the
+    /// secret. Unlike `query`, it does not update the last seen time, for the student is not the one looking.
+    pub async fn find(&self, query_string: &str) -> Result<FreshmanBasic> {
+        let student_basic: Option<FreshmanBasic> = sqlx::query_as(
+            "SELECT
+                    name, uid, student_id, college, major, campus, building, room, bed, secret,
+                    counselor_name, counselor_tel, visible
+                FROM freshman.students
+                WHERE student_id = $1 OR ticket = $1
+                LIMIT 1",
+        )
+        .bind(query_string)
+        .fetch_optional(self.pool)
+        .await?;
+
+        student_basic.ok_or_else(|| ApiError::new(FreshmanError::NoSuchAccount))
+    }
+
     /// Bind student id with uid.
     pub async fn bind(&self, student_id: &str, uid: Option<i32>) -> Result<()> {
         sqlx::query("UPDATE freshman.students SET uid = $1 WHERE student_id = $2")
@@ -1,6 +1,6 @@
 use chrono::{DateTime, Local, Utc};
 use rand::Rng;
-use sqlx::PgPool;
+use sqlx::{Executor, PgPool, Postgres};
 
 use crate::error::Result;
 use crate::models::mall::{Comment, PubComment};
@@ -53,7 +53,20 @@ pub async fn publish_comment(db: &PgPool, uid: i32, new: &PubComment) -> Result<
     Ok(com_code)
 }
 
-pub async fn delete_comment(db: &PgPool, com_code: String) -> Result<()> {
+/// Get the uid of the author of the comment.
+pub async fn get_comment_author(db: &PgPool, com_code: &str) -> Result<Option<i32>> {
+    let author: Option<(i32,)> =
+        sqlx::query_as("SELECT user_code FROM mall.comment WHERE com_code = $1")
+            .bind(com_code)
+            .fetch_optional(db)
+            .await?;
+    Ok(author.map(|(uid,)| uid))
+}
+
+pub async fn delete_comment<'c, E>(db: E, com_code: String) -> Result<()>
+where
+    E: Executor<'c, Database = Postgres>,
+{
     let _ = sqlx::query(
         "
             UPDATE
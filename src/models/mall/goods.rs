@@ -1,6 +1,6 @@
 use chrono::{DateTime, Local};
 use rand::Rng;
-use sqlx::PgPool;
+use sqlx::{Executor, PgPool, Postgres};
 use wechat_sdk::wechat::CheckResult;
 
 use crate::error::{ApiError, Result};
@@ -93,7 +93,20 @@ pub async fn get_goods_detail(db: &PgPool, item_code: &String) -> Result<DetailI
     detail.ok_or_else(|| ApiError::new(MallError::NoSuchGoods))
 }
 
-pub async fn delete_goods(db: &PgPool, pub_code: &String) -> Result<i32> {
+/// Get the uid of the publisher of the goods.
+pub async fn get_goods_publisher(db: &PgPool, pub_code: &str) -> Result<Option<i32>> {
+    let publisher: Option<(i32,)> =
+        sqlx::query_as("SELECT publisher FROM mall.publish WHERE pub_code = $1")
+            .bind(pub_code)
+            .fetch_optional(db)
+            .await?;
+    Ok(publisher.map(|(uid,)| uid))
+}
+
+pub async fn delete_goods<'c, E>(db: E, pub_code: &String) -> Result<i32>
+where
+    E: Executor<'c, Database = Postgres>,
+{
     let _ = sqlx::query(
         "
             UPDATE mall.publish
@@ -5,9 +5,16 @@ use serde::{Deserialize, Serialize};
 
 pub use person::get_default_avatar;
 pub use person::get_open_id;
+pub use role::{add_role, remove_role, role_permissions, Grants, Permission, ADMIN_ROLE};
+pub use session::SessionRegistry;
+pub use token::RefreshToken;
 
 mod identity;
+mod password;
 mod person;
+mod role;
+mod session;
+mod token;
 
 /* Constants at the edge between self and database. */
 
@@ -39,6 +46,16 @@ pub enum UserError {
     NoSupport = 59,
     #[error("无该用户openid,用户需在近两小时访问过小程序")]
     NoUserOpenId = 60,
+    #[error("登录已过期，请重新登录")]
+    RefreshTokenInvalid = 61,
+    #[error("登录凭据已被使用，请重新登录")]
+    RefreshTokenReused = 62,
+    #[error("不能解绑唯一的登录方式")]
+    LastAuthentication = 63,
+    #[error("未绑定该登录方式")]
+    NoSuchAuthentication = 64,
+    #[error("找不到该角色")]
+    NoSuchRole = 65,
 }
 
 /* Models */
@@ -53,10 +70,34 @@ pub struct Authentication {
     pub login_type: i32,
     /// Username or wechat token (id).
     pub account: String,
-    /// Password if uses username.
+    /// Password hash if uses username. Legacy rows may hold plaintext, see the `password` module.
     pub credential: Option<String>,
 }
 
+/// Login method bound to an account, without the credential.
+#[derive(Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct LoginMethod {
+    /// login type.
+    pub login_type: i32,
+    /// Username or wechat open id.
+    pub account: String,
+}
+
+/// Change of login methods, recorded by the trigger on table "authentication".
+#[derive(Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticationLog {
+    /// Change time.
+    pub ts: NaiveDateTime,
+    /// login type. None for changes recorded before it was logged.
+    pub login_type: Option<i32>,
+    /// Account of the new login method for "bind", or of the old one for "update" and "unbind".
+    pub account: String,
+    /// "bind", "update" or "unbind". None for changes recorded before it was logged.
+    pub action: Option<String>,
+}
+
 /// Base information of each account.
 #[derive(sqlx::FromRow, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -94,7 +135,8 @@ pub struct Identity {
     pub uid: i32,
     /// Student id
     pub student_id: String,
-    /// OA secret(password)
+    /// OA secret(password), encrypted by `crypto` once saved. It is never sent to clients.
+    #[serde(skip_serializing)]
     pub oa_secret: String,
     /// Whether OA certified or not
     pub oa_certified: bool,
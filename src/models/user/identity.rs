@@ -1,3 +1,5 @@
+use sqlx::PgPool;
+
 use crate::error::{ApiError, Result};
 use crate::models::user::UserError;
 
@@ -5,8 +7,19 @@ use super::Identity;
 use crate::bridge::{
     AgentManager, HostError, PortalAuthRequest, RequestFrame, RequestPayload, ResponsePayload,
 };
+use crate::crypto::{self, KEYRING};
 
 impl Identity {
+    /// Validate the OA account with the agent, and encrypt the secret to be stored. It is done before the
+    /// transaction saving the identity, which is not held open while waiting for the agent.
+    pub async fn certify(&mut self, agent: &AgentManager) -> Result<()> {
+        // Throw UserError::OaSecretFailed if password is wrong.
+        validate_oa_account(&self.student_id, &self.oa_secret, agent).await?;
+        self.oa_certified = true;
+        self.oa_secret = crypto::encrypt(&self.oa_secret)?;
+        Ok(())
+    }
+
     pub fn new(uid: i32, student_id: String) -> Self {
         Self {
             uid,
@@ -14,6 +27,34 @@ impl Identity {
             ..Identity::default()
         }
     }
+
+    /// Encrypt OA secrets stored in plaintext, and re-encrypt those under retired master keys with the current
+    /// one. Return the count of updated rows.
+    pub async fn encrypt_secrets(client: &PgPool) -> Result<u64> {
+        let secrets: Vec<(i32, String)> =
+            sqlx::query_as("SELECT uid, oa_secret FROM public.identity WHERE oa_secret IS NOT NULL")
+                .fetch_all(client)
+                .await?;
+        let mut count = 0;
+
+        for (uid, stored) in secrets {
+            let rewrapped = match KEYRING.rewrap(&stored)? {
+                Some(rewrapped) => rewrapped,
+                None => continue,
+            };
+            // Skip the row if the user changes the secret meanwhile.
+            let result = sqlx::query(
+                "UPDATE public.identity SET oa_secret = $1 WHERE uid = $2 AND oa_secret = $3",
+            )
+            .bind(rewrapped)
+            .bind(uid)
+            .bind(stored)
+            .execute(client)
+            .await?;
+            count += result.rows_affected();
+        }
+        Ok(count)
+    }
 }
 
 async fn send_auth_request(student_id: &str, oa_secret: &str, agent: &AgentManager) -> Result<()> {
@@ -0,0 +1,83 @@
+//! Password hashing for password-based login.
+//!
+//! Passwords are stored as argon2id hashes in PHC string format, like `$argon2id$v=19$m=19456,t=2,p=1$...`.
+//! Rows written before hashing was introduced hold plaintext, and they are rehashed on the next successful login.
+
+use anyhow::Result;
+use argon2::{Config, Variant, Version};
+use rand::Rng;
+
+const SALT_LENGTH: usize = 16;
+/// Hash which no password is expected to match. Logins to unknown accounts are verified against it, so that they
+/// take as long as a wrong password and do not tell whether the account exists.
+const DUMMY_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$a2l0ZS1kdW1teS1zYWx0IQ$7gYXixhSK9vif01wjtoBrgVICiVOz8SLjNw0H7t8upw";
+
+fn config() -> Config<'static> {
+    Config {
+        variant: Variant::Argon2id,
+        version: Version::Version13,
+        mem_cost: 19456,
+        time_cost: 2,
+        lanes: 1,
+        ..Config::default()
+    }
+}
+
+/// Whether the stored credential is a hash, rather than a legacy plaintext password.
+pub fn is_hashed(credential: &str) -> bool {
+    credential.starts_with("$argon2")
+}
+
+/// Hash the password with a random salt. It takes tens of milliseconds, so it runs on the blocking thread pool.
+pub async fn hash(password: &str) -> Result<String> {
+    let password = password.to_string();
+    let salt: [u8; SALT_LENGTH] = rand::thread_rng().gen();
+
+    let hash =
+        tokio::task::spawn_blocking(move || argon2::hash_encoded(password.as_bytes(), &salt, &config()))
+            .await??;
+    Ok(hash)
+}
+
+/// Verify the password against the stored credential, which may be a hash or a legacy plaintext.
+pub async fn verify(credential: &str, password: &str) -> Result<bool> {
+    if !is_hashed(credential) {
+        return Ok(constant_time_eq(credential.as_bytes(), password.as_bytes()));
+    }
+    let credential = credential.to_string();
+    let password = password.to_string();
+
+    let result =
+        tokio::task::spawn_blocking(move || argon2::verify_encoded(&credential, password.as_bytes()))
+            .await??;
+    Ok(result)
+}
+
+/// Spend the time of a verification on an account which does not exist.
+pub async fn verify_dummy(password: &str) -> Result<()> {
+    verify(DUMMY_HASH, password).await?;
+    Ok(())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn hash_and_verify() {
+        let hash = hash("password").await.unwrap();
+
+        assert!(is_hashed(&hash));
+        assert!(verify(&hash, "password").await.unwrap());
+        assert!(!verify(&hash, "Password").await.unwrap());
+        // Legacy plaintext credentials.
+        assert!(verify("password", "password").await.unwrap());
+        assert!(!verify("password", "passwor").await.unwrap());
+        assert!(!verify(DUMMY_HASH, "").await.unwrap());
+    }
+}
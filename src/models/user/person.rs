@@ -1,13 +1,13 @@
 use chrono::Utc;
-use sqlx::PgPool;
+use sqlx::{Executor, PgPool, Postgres, Transaction};
 
 use crate::error::ApiError;
 use crate::error::Result;
 
-use super::{Authentication, Identity, Person, UserError};
+use super::password;
+use super::{Authentication, AuthenticationLog, Identity, LoginMethod, Person, UserError};
 use super::{LOGIN_BY_PASSWORD, LOGIN_BY_WECHAT};
-use crate::bridge::AgentManager;
-use crate::models::user::identity::validate_oa_account;
+use crate::models::PageView;
 
 impl Authentication {
     pub fn from_password(username: String, password: String) -> Self {
@@ -29,21 +29,39 @@ impl Authentication {
     }
 
     /// Login by a password, return a Person structure if success. Otherwise, an UserError will be returned.
+    /// A legacy plaintext credential is replaced by its hash once the password is verified.
     pub async fn password_login(&self, client: &PgPool) -> Result<Person> {
-        let user: Option<Person> = sqlx::query_as(
-            "SELECT p.uid, nick_name, avatar, is_disabled, is_admin, gender, country, province, city, language, create_time
-                FROM public.person p
-                RIGHT JOIN authentication auth on p.uid = auth.uid
-                WHERE auth.login_type = 1 AND auth.account = $1 AND auth.credential = $2 LIMIT 1"
+        let stored: Option<(i32, Option<String>)> = sqlx::query_as(
+            "SELECT uid, credential FROM authentication WHERE login_type = 1 AND account = $1 LIMIT 1",
         )
-            .bind(&self.account)
-            .bind(&self.credential)
-            .fetch_optional(client)
-            .await?;
-        match user {
-            Some(user) => Ok(user),
-            None => Err(ApiError::new(UserError::LoginFailed)),
+        .bind(&self.account)
+        .fetch_optional(client)
+        .await?;
+        let password = self.credential.as_deref().unwrap_or_default();
+
+        let (uid, credential) = match stored {
+            Some((uid, Some(credential))) => {
+                if !password::verify(&credential, password).await? {
+                    return Err(ApiError::new(UserError::LoginFailed));
+                }
+                (uid, credential)
+            }
+            _ => {
+                password::verify_dummy(password).await?;
+                return Err(ApiError::new(UserError::LoginFailed));
+            }
+        };
+        if !password::is_hashed(&credential) {
+            let hash = password::hash(password).await?;
+            sqlx::query("UPDATE authentication SET credential = $1 WHERE uid = $2 AND login_type = 1")
+                .bind(hash)
+                .bind(uid)
+                .execute(client)
+                .await?;
         }
+        Person::get(client, uid)
+            .await
+            .map_err(|_| ApiError::new(UserError::LoginFailed))
     }
 
     pub async fn wechat_login(&self, client: &PgPool) -> Result<Person> {
@@ -69,7 +87,15 @@ impl Person {
     }
 
     /// Bind authentication, if auth type already exists, this function will override the old record.
-    pub async fn update_authentication(&self, client: &PgPool, auth: &Authentication) -> Result<()> {
+    /// Passwords are hashed before stored.
+    pub async fn update_authentication<'c, E>(&self, client: E, auth: &Authentication) -> Result<()>
+    where
+        E: Executor<'c, Database = Postgres>,
+    {
+        let credential = match (&auth.credential, auth.login_type) {
+            (Some(password), LOGIN_BY_PASSWORD) => Some(password::hash(password).await?),
+            (credential, _) => credential.clone(),
+        };
         // Note: Alter username is not allowed.
         let _ = sqlx::query(
             "INSERT INTO
@@ -80,13 +106,72 @@ impl Person {
         .bind(self.uid)
         .bind(auth.login_type)
         .bind(&auth.account)
-        .bind(&auth.credential)
+        .bind(&credential)
         .execute(client)
         .await?;
 
         Ok(())
     }
 
+    /// Login methods bound to the user.
+    pub async fn list_authentications(client: &PgPool, uid: i32) -> Result<Vec<LoginMethod>> {
+        let methods: Vec<LoginMethod> = sqlx::query_as(
+            "SELECT login_type, account FROM authentication WHERE uid = $1 ORDER BY login_type",
+        )
+        .bind(uid)
+        .fetch_all(client)
+        .await?;
+        Ok(methods)
+    }
+
+    /// Unbind the login method. The last login method can not be removed, or the user could never log in again.
+    /// It is done in the transaction given, which the caller commits.
+    pub async fn remove_authentication(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        login_type: i32,
+    ) -> Result<()> {
+        // Lock the user, so that concurrent requests can not remove the last two methods at the same time.
+        sqlx::query("SELECT uid FROM person WHERE uid = $1 FOR UPDATE")
+            .bind(self.uid)
+            .execute(&mut *tx)
+            .await?;
+        let types: Vec<(i32,)> = sqlx::query_as("SELECT login_type FROM authentication WHERE uid = $1")
+            .bind(self.uid)
+            .fetch_all(&mut *tx)
+            .await?;
+        if !types.contains(&(login_type,)) {
+            return Err(ApiError::new(UserError::NoSuchAuthentication));
+        }
+        if types.len() == 1 {
+            return Err(ApiError::new(UserError::LastAuthentication));
+        }
+        sqlx::query("DELETE FROM authentication WHERE uid = $1 AND login_type = $2")
+            .bind(self.uid)
+            .bind(login_type)
+            .execute(&mut *tx)
+            .await?;
+        Ok(())
+    }
+
+    /// Changes of login methods of the user, latest first.
+    pub async fn authentication_history(
+        client: &PgPool,
+        uid: i32,
+        page: &PageView,
+    ) -> Result<Vec<AuthenticationLog>> {
+        let logs: Vec<AuthenticationLog> = sqlx::query_as(
+            "SELECT ts, login_type, account, action FROM authentication_log
+                WHERE uid = $1 ORDER BY ts DESC OFFSET $2 LIMIT $3",
+        )
+        .bind(uid)
+        .bind(page.offset(50) as i64)
+        .bind(page.count(50) as i64)
+        .fetch_all(client)
+        .await?;
+        Ok(logs)
+    }
+
     pub async fn register(&mut self, client: &PgPool) -> Result<()> {
         let uid: Option<(i32,)> = sqlx::query_as(
             "INSERT INTO public.person
@@ -110,11 +195,16 @@ impl Person {
         Ok(())
     }
 
-    pub async fn update(&self, client: &PgPool) -> Result<()> {
+    pub async fn update<'c, E>(&self, client: E) -> Result<()>
+    where
+        E: Executor<'c, Database = Postgres>,
+    {
         sqlx::query(
-            "UPDATE public.person SET gender = $1, country = $2, province = $3, city = $4, avatar = $5\
-                WHERE uid = $6",
+            "UPDATE public.person
+                SET nick_name = $1, gender = $2, country = $3, province = $4, city = $5, avatar = $6
+                WHERE uid = $7",
         )
+        .bind(&self.nick_name)
         .bind(self.gender)
         .bind(&self.country)
         .bind(&self.province)
@@ -179,17 +269,11 @@ impl Person {
         Ok(identity)
     }
 
-    /// Set identity info
-    pub async fn set_identity(
-        &self,
-        client: &PgPool,
-        identity: &mut Identity,
-        agent: &AgentManager,
-    ) -> Result<()> {
-        // Throw UserError::OaSecretFailed if password is wrong.
-        validate_oa_account(&identity.student_id, &identity.oa_secret, agent).await?;
-        identity.oa_certified = true;
-
+    /// Set identity info, which is certified by `Identity::certify` before.
+    pub async fn set_identity<'c, E>(&self, client: E, identity: &Identity) -> Result<()>
+    where
+        E: Executor<'c, Database = Postgres>,
+    {
         let _ = sqlx::query(
             "INSERT INTO public.identity (uid, student_id, oa_secret, oa_certified)
                 VALUES ($1, $2, $3, true)
@@ -0,0 +1,120 @@
+//! Roles and permissions of users.
+//!
+//! A user may have several roles, like "admin" or "mall-moderator", in table "person_role". Each role grants
+//! permissions in table "role_permission". Both are carried in the access token, so they take effect after
+//! the user logs in or refreshes the token.
+
+use sqlx::{Executor, PgPool, Postgres};
+use strum_macros::AsRefStr;
+
+use crate::error::{ApiError, Result};
+
+use super::UserError;
+
+/// Role of administrators, which is given in the database only.
+pub const ADMIN_ROLE: &str = "admin";
+
+/// Permissions checked by handlers. Administrators have all of them.
+#[derive(Debug, Clone, Copy, PartialEq, AsRefStr)]
+pub enum Permission {
+    /// Manage users and their authentications.
+    #[strum(serialize = "user.manage")]
+    ManageUser,
+    /// View agent status and purge the agent cache.
+    #[strum(serialize = "agent.manage")]
+    ManageAgent,
+    /// View attachment details.
+    #[strum(serialize = "attachment.manage")]
+    ManageAttachment,
+    /// Remove goods and comments of others in the mall.
+    #[strum(serialize = "mall.moderate")]
+    ModerateMall,
+    /// Query the audit log.
+    #[strum(serialize = "audit.view")]
+    ViewAudit,
+    /// Delete events published by others.
+    #[strum(serialize = "event.manage")]
+    ManageEvent,
+    /// Look up freshmen without their secrets, to help them at the registration desk.
+    #[strum(serialize = "freshman.manage")]
+    ManageFreshman,
+    /// Give roles to users and take them back.
+    #[strum(serialize = "role.manage")]
+    ManageRole,
+}
+
+/// Roles and permissions of a user.
+#[derive(Debug, Default)]
+pub struct Grants {
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+}
+
+impl Grants {
+    pub async fn of(client: &PgPool, uid: i32) -> Result<Self> {
+        let roles: Vec<(String,)> =
+            sqlx::query_as("SELECT role FROM person_role WHERE uid = $1 ORDER BY role")
+                .bind(uid)
+                .fetch_all(client)
+                .await?;
+        let permissions: Vec<(String,)> = sqlx::query_as(
+            "SELECT DISTINCT rp.permission
+                FROM person_role pr INNER JOIN role_permission rp ON pr.role = rp.role
+                WHERE pr.uid = $1
+                ORDER BY rp.permission",
+        )
+        .bind(uid)
+        .fetch_all(client)
+        .await?;
+
+        Ok(Self {
+            roles: roles.into_iter().map(|(role,)| role).collect(),
+            permissions: permissions.into_iter().map(|(permission,)| permission).collect(),
+        })
+    }
+}
+
+/// Permissions granted by the role. Empty for unknown roles.
+pub async fn role_permissions(client: &PgPool, role: &str) -> Result<Vec<String>> {
+    let permissions: Vec<(String,)> =
+        sqlx::query_as("SELECT permission FROM role_permission WHERE role = $1 ORDER BY permission")
+            .bind(role)
+            .fetch_all(client)
+            .await?;
+    Ok(permissions.into_iter().map(|(permission,)| permission).collect())
+}
+
+/// Give the role to the user. Roles are defined by the permissions they grant, so unknown ones are refused.
+pub async fn add_role<'c, E>(client: E, uid: i32, role: &str) -> Result<()>
+where
+    E: Executor<'c, Database = Postgres>,
+{
+    let (known,): (bool,) = sqlx::query_as(
+        "WITH known AS (SELECT 1 FROM role_permission WHERE role = $2 LIMIT 1),
+            inserted AS (
+                INSERT INTO person_role (uid, role) SELECT $1, $2 FROM known ON CONFLICT DO NOTHING
+            )
+        SELECT EXISTS (SELECT 1 FROM known)",
+    )
+    .bind(uid)
+    .bind(role)
+    .fetch_one(client)
+    .await?;
+    if !known {
+        return Err(ApiError::new(UserError::NoSuchRole));
+    }
+    Ok(())
+}
+
+/// Take the role back from the user.
+pub async fn remove_role<'c, E>(client: E, uid: i32, role: &str) -> Result<()>
+where
+    E: Executor<'c, Database = Postgres>,
+{
+    sqlx::query("DELETE FROM person_role WHERE uid = $1 AND role = $2")
+        .bind(uid)
+        .bind(role)
+        .execute(client)
+        .await?;
+    Ok(())
+}
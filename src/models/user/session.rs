@@ -0,0 +1,176 @@
+//! Login sessions, identified by the `jti` claim of access tokens.
+//!
+//! A session is created on each login, and the refresh tokens rotated from that login belong to it. Revoking
+//! a session rejects its access tokens and refresh tokens. Results of session checks are cached in memory
+//! for a short while, so that the database is not queried on every request.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::error::Result;
+
+/// How long a session check is cached. Sessions revoked by other instances take effect after it.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+/// Stale entries are removed when the cache grows to this size.
+const CACHE_SIZE: usize = 10000;
+
+struct CacheEntry {
+    uid: i32,
+    active: bool,
+    time: Instant,
+}
+
+#[derive(Clone, Default)]
+pub struct SessionRegistry {
+    /// Session checks by jti, or by uid and issue time for tokens issued before sessions were introduced.
+    cache: Arc<Mutex<HashMap<String, CacheEntry>>>,
+}
+
+impl SessionRegistry {
+    /// Create a session for a new login, return its jti.
+    pub async fn create(&self, client: &PgPool, uid: i32) -> Result<Uuid> {
+        let jti = Uuid::new_v4();
+
+        sqlx::query("INSERT INTO session (jti, uid) VALUES ($1, $2)")
+            .bind(jti)
+            .bind(uid)
+            .execute(client)
+            .await?;
+        Ok(jti)
+    }
+
+    fn cached(&self, key: &str) -> Option<bool> {
+        let cache = self.cache.lock().unwrap();
+
+        cache
+            .get(key)
+            .filter(|entry| entry.time.elapsed() < CACHE_TTL)
+            .map(|entry| entry.active)
+    }
+
+    /// Whether the token of the session is still accepted, that is, the session is not revoked and the account
+    /// is not disabled. Tokens without a jti can not be revoked one by one, and they are checked against the
+    /// account, and rejected if issued before all sessions of the user are revoked.
+    pub async fn is_active(&self, client: &PgPool, uid: i32, jti: &str, iat: i64) -> Result<bool> {
+        let key = if jti.is_empty() {
+            format!("{}@{}", uid, iat)
+        } else {
+            jti.to_string()
+        };
+        if let Some(active) = self.cached(&key) {
+            return Ok(active);
+        }
+
+        let active: Option<(bool,)> = if jti.is_empty() {
+            sqlx::query_as(
+                "SELECT NOT is_disabled AND (revoked_before IS NULL OR revoked_before <= to_timestamp($2))
+                    FROM person WHERE uid = $1",
+            )
+            .bind(uid)
+            .bind(iat as f64)
+            .fetch_optional(client)
+            .await?
+        } else {
+            let jti = match Uuid::parse_str(jti) {
+                Ok(jti) => jti,
+                Err(_) => return Ok(false),
+            };
+            sqlx::query_as(
+                "SELECT s.revoked_at IS NULL AND NOT p.is_disabled
+                    FROM session s INNER JOIN person p ON s.uid = p.uid
+                    WHERE s.jti = $1 AND s.uid = $2",
+            )
+            .bind(jti)
+            .bind(uid)
+            .fetch_optional(client)
+            .await?
+        };
+        let active = matches!(active, Some((true,)));
+
+        let entry = CacheEntry {
+            uid,
+            active,
+            time: Instant::now(),
+        };
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= CACHE_SIZE {
+            cache.retain(|_, entry| entry.time.elapsed() < CACHE_TTL);
+        }
+        cache.insert(key, entry);
+        Ok(active)
+    }
+
+    /// Revoke the session with its refresh tokens, used on logout and when a used refresh token is presented
+    /// again.
+    pub async fn revoke(&self, client: &PgPool, jti: Uuid) -> Result<()> {
+        let mut tx = client.begin().await?;
+        sqlx::query("UPDATE session SET revoked_at = now() WHERE jti = $1 AND revoked_at IS NULL")
+            .bind(jti)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("UPDATE refresh_token SET revoked = true WHERE family = $1")
+            .bind(jti)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        self.cache.lock().unwrap().remove(&jti.to_string());
+        Ok(())
+    }
+
+    /// Revoke all sessions of the user, used when an account is stolen or disabled. Tokens without a jti issued
+    /// before now are rejected too. The transaction, with other
+    /// changes like audit records in it, is committed before cached checks are dropped, so that they are not
+    /// cached again from the rows before the change.
+    pub async fn revoke_all(&self, mut tx: Transaction<'_, Postgres>, uid: i32) -> Result<()> {
+        sqlx::query("UPDATE session SET revoked_at = now() WHERE uid = $1 AND revoked_at IS NULL")
+            .bind(uid)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("UPDATE refresh_token SET revoked = true WHERE uid = $1")
+            .bind(uid)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("UPDATE person SET revoked_before = now() WHERE uid = $1")
+            .bind(uid)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        self.cache.lock().unwrap().retain(|_, entry| entry.uid != uid);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::user::Person;
+    use crate::services::fake::test_pool;
+
+    #[tokio::test]
+    #[ignore = "needs KITE_TEST_DB"]
+    pub async fn revoke_tokens_without_jti() {
+        let pool = test_pool();
+        let sessions = SessionRegistry::default();
+        let mut person = Person::default();
+        person.register(&pool).await.unwrap();
+        let issued = chrono::Utc::now().timestamp() - 60;
+        assert!(sessions.is_active(&pool, person.uid, "", issued).await.unwrap());
+        // Tokens issued before expiration was introduced carry no issue time either.
+        assert!(sessions.is_active(&pool, person.uid, "", 0).await.unwrap());
+
+        sessions
+            .revoke_all(pool.begin().await.unwrap(), person.uid)
+            .await
+            .unwrap();
+        assert!(!sessions.is_active(&pool, person.uid, "", issued).await.unwrap());
+        assert!(!sessions.is_active(&pool, person.uid, "", 0).await.unwrap());
+        let reissued = chrono::Utc::now().timestamp() + 1;
+        assert!(sessions.is_active(&pool, person.uid, "", reissued).await.unwrap());
+    }
+}
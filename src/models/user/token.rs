@@ -0,0 +1,107 @@
+//! Refresh tokens, used to obtain new access tokens without logging in again.
+//!
+//! Each refresh token can be used only once, and it is replaced by a new one in the same family on use.
+//! If a used token is presented again, it is likely stolen, and the whole family is revoked together with its
+//! access tokens. The family is the login session, see `SessionRegistry`.
+
+use chrono::{Duration, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::config::CONFIG;
+use crate::error::{ApiError, Result};
+
+use super::{SessionRegistry, UserError};
+
+pub struct RefreshToken;
+
+fn digest(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+impl RefreshToken {
+    async fn insert(client: &PgPool, uid: i32, family: Uuid) -> Result<String> {
+        let token = format!("{}{}", Uuid::new_v4().to_simple(), Uuid::new_v4().to_simple());
+        let expires_at = Utc::now() + Duration::seconds(CONFIG.server.refresh_token_ttl);
+
+        sqlx::query(
+            "INSERT INTO refresh_token (token, family, uid, expires_at) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(digest(&token))
+        .bind(family)
+        .bind(uid)
+        .bind(expires_at)
+        .execute(client)
+        .await?;
+        Ok(token)
+    }
+
+    /// Issue a refresh token for a new login session.
+    pub async fn issue(client: &PgPool, uid: i32, session: Uuid) -> Result<String> {
+        Self::insert(client, uid, session).await
+    }
+
+    /// Use the refresh token, return the uid, the session and the next refresh token.
+    pub async fn rotate(
+        client: &PgPool,
+        sessions: &SessionRegistry,
+        token: &str,
+    ) -> Result<(i32, Uuid, String)> {
+        let token = digest(token);
+        let current: Option<(i32, Uuid)> = sqlx::query_as(
+            "UPDATE refresh_token SET used_at = now()
+                WHERE token = $1 AND used_at IS NULL AND NOT revoked AND expires_at > now()
+                RETURNING uid, family",
+        )
+        .bind(&token)
+        .fetch_optional(client)
+        .await?;
+
+        if let Some((uid, family)) = current {
+            let next = Self::insert(client, uid, family).await?;
+            return Ok((uid, family, next));
+        }
+
+        let reused: Option<(Uuid,)> =
+            sqlx::query_as("SELECT family FROM refresh_token WHERE token = $1 AND used_at IS NOT NULL")
+                .bind(&token)
+                .fetch_optional(client)
+                .await?;
+        match reused {
+            Some((family,)) => {
+                sessions.revoke(client, family).await?;
+                Err(ApiError::new(UserError::RefreshTokenReused))
+            }
+            None => Err(ApiError::new(UserError::RefreshTokenInvalid)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::user::Person;
+    use crate::services::fake::{load_config, test_pool};
+
+    #[tokio::test]
+    #[ignore = "needs KITE_TEST_DB"]
+    pub async fn revoke_session_on_reuse() {
+        load_config();
+        let pool = test_pool();
+        let sessions = SessionRegistry::default();
+        let mut person = Person::default();
+        person.register(&pool).await.unwrap();
+
+        let session = sessions.create(&pool, person.uid).await.unwrap();
+        let first = RefreshToken::issue(&pool, person.uid, session).await.unwrap();
+        RefreshToken::rotate(&pool, &sessions, &first).await.unwrap();
+        let jti = session.to_string();
+        assert!(sessions.is_active(&pool, person.uid, &jti, 0).await.unwrap());
+
+        // The cached check is dropped too, so access tokens of the session are rejected at once.
+        let reused = RefreshToken::rotate(&pool, &sessions, &first).await;
+        assert!(reused.is_err());
+        assert!(!sessions.is_active(&pool, person.uid, &jti, 0).await.unwrap());
+    }
+}
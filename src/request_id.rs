@@ -0,0 +1,57 @@
+//! Request ID, used to trace a single request in logs of both the server and agents.
+//!
+//! The ID is taken from the `X-Request-Id` header or generated by the `RequestIdentity` middleware, and kept
+//! in a task-local variable while the request is handled, so that agent requests and error responses deep in
+//! the call stack can read it without passing it around.
+
+use std::future::Future;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Max length of IDs accepted from clients.
+const MAX_ID_LENGTH: usize = 64;
+
+pub fn generate() -> String {
+    uuid::Uuid::new_v4().to_simple().to_string()
+}
+
+/// Whether the ID given by client can be used. Only letters, digits, '-', '_' and '.' are allowed.
+pub fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// ID of the request being handled, or None outside of a request, like in background tasks.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+pub fn sync_scope<F, R>(id: String, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    REQUEST_ID.sync_scope(id, f)
+}
+
+pub async fn scope<F: Future>(id: String, f: F) -> F::Output {
+    REQUEST_ID.scope(id, f).await
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    pub fn is_valid() {
+        assert!(super::is_valid("a1b2-c3_d4.e5"));
+        assert!(super::is_valid(&super::generate()));
+
+        assert!(!super::is_valid(""));
+        assert!(!super::is_valid("with space"));
+        assert!(!super::is_valid("line\nbreak"));
+        assert!(!super::is_valid(&"a".repeat(65)));
+    }
+}
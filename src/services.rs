@@ -3,6 +3,7 @@
 //! some permission check in acl_middleware
 
 use std::io::Read;
+use std::sync::Arc;
 
 use actix_web::http::HeaderValue;
 use actix_web::{web, App, HttpResponse, HttpServer};
@@ -13,8 +14,14 @@ use wechat_sdk::client::{WeChatClient, WeChatClientBuilder};
 
 use crate::bridge::AgentManager;
 use crate::config::CONFIG;
+use crate::crypto;
+use crate::error::{ApiError, Result};
+use crate::models::user::{Grants, Identity, Permission, Person, SessionRegistry};
+use crate::models::CommonError;
 
 mod auth;
+#[cfg(test)]
+pub(crate) mod fake;
 mod handlers;
 mod middlewares;
 mod response;
@@ -23,12 +30,12 @@ mod response;
 pub struct AppState {
     pub(crate) pool: PgPool,
     pub(crate) agents: AgentManager,
+    pub(crate) sessions: SessionRegistry,
     wx_client: WeChatClient,
 }
 
-pub async fn server_main() -> std::io::Result<()> {
-    // Create database pool.
-    let pool = PgPoolOptions::new()
+async fn connect_database() -> PgPool {
+    PgPoolOptions::new()
         .max_connections(10)
         .after_connect(|conn| {
             Box::pin(async move {
@@ -38,11 +45,18 @@ pub async fn server_main() -> std::io::Result<()> {
         })
         .connect(&CONFIG.server.db)
         .await
-        .expect("Could not create database pool");
+        .expect("Could not create database pool")
+}
+
+pub async fn server_main() -> std::io::Result<()> {
+    // Create database pool.
+    let pool = connect_database().await;
+    // Check crypto keys before serving, rather than on the first request.
+    lazy_static::initialize(&crypto::KEYRING);
 
     // Logger
     set_logger("kite.log");
-    let log_string = "%a - - [%t] \"%r\" %s %b %D \"%{User-Agent}i\"";
+    let log_string = "%a - - [%t] \"%r\" %s %b %D \"%{User-Agent}i\" %{X-Request-Id}o";
 
     // Load white list
     let mut file = std::fs::File::open("ip-whitelist.txt")
@@ -57,7 +71,7 @@ pub async fn server_main() -> std::io::Result<()> {
         .secret(&CONFIG.wechat.secret)
         .build();
 
-    let agents = AgentManager::new(&CONFIG.host.bind);
+    let agents = AgentManager::new(&CONFIG.host);
     let _agents = agents.clone();
     tokio::spawn(async move {
         _agents.listen().await;
@@ -66,19 +80,27 @@ pub async fn server_main() -> std::io::Result<()> {
     let app_state = AppState {
         pool: pool.clone(),
         agents: agents.clone(),
+        sessions: SessionRegistry::default(),
         wx_client,
     };
 
+    let limiter = Arc::new(middlewares::RateLimiter::new(
+        &CONFIG.server.rate_limit,
+        CONFIG.server.trusted_proxies,
+    ));
+
     use crate::models::sc::activity_update_daemon;
 
-    tokio::spawn(activity_update_daemon(pool, agents));
+    tokio::spawn(activity_update_daemon(pool, agents.background()));
 
     // Run actix-web services.
     let mut server = HttpServer::new(move || {
         App::new()
             .wrap(middlewares::Auth {})
+            .wrap(middlewares::RateLimit::new(limiter.clone()))
             .wrap(middlewares::Reject::new(&buffer))
             .wrap(actix_web::middleware::Compress::default())
+            .wrap(middlewares::RequestIdentity)
             .wrap(actix_web::middleware::Logger::new(log_string))
             .app_data(web::Data::new(app_state.clone()))
             .configure(routes)
@@ -102,6 +124,19 @@ pub async fn server_main() -> std::io::Result<()> {
     server.run().await
 }
 
+/// One-off command to encrypt OA secrets stored in plaintext, and re-encrypt those under retired keys after
+/// the master key is rotated.
+pub async fn encrypt_secrets_main() -> Result<()> {
+    let pool = connect_database().await;
+    let count = Identity::encrypt_secrets(&pool).await?;
+
+    println!(
+        "{} OA secrets are encrypted with key \"{}\".",
+        count, CONFIG.crypto.current
+    );
+    Ok(())
+}
+
 fn routes(app: &mut web::ServiceConfig) {
     use handlers::*;
 
@@ -112,7 +147,15 @@ fn routes(app: &mut web::ServiceConfig) {
             .route("/", web::get().to(|| HttpResponse::Ok().body("Hello world")))
             // User routes
             .service(user::login)
+            .service(user::refresh_session)
+            .service(user::logout)
+            .service(user::revoke_user_sessions)
+            .service(user::add_user_role)
+            .service(user::remove_user_role)
             .service(user::bind_authentication)
+            .service(user::list_authentications)
+            .service(user::remove_authentication)
+            .service(user::get_authentication_history)
             .service(user::list_users)
             .service(user::create_user)
             .service(user::get_user_detail)
@@ -135,6 +178,8 @@ fn routes(app: &mut web::ServiceConfig) {
             .service(motto::get_one_motto)
             // Event and activity routes
             .service(event::list_events)
+            .service(event::create_event)
+            .service(event::delete_event)
             .service(event::get_sc_score_list)
             .service(event::get_sc_score)
             .service(event::get_sc_event_list)
@@ -154,6 +199,11 @@ fn routes(app: &mut web::ServiceConfig) {
             .service(status::get_timestamp)
             .service(status::ping_agent)
             .service(status::get_agent_list)
+            .service(status::purge_agent_cache)
+            // Audit log
+            .service(audit::query_audit_log)
+            // Agent connection over WebSocket
+            .service(agent::connect_agent)
             // Pay and room balance
             .service(pay::query_room_balance)
             .service(pay::query_room_bills_by_day)
@@ -210,6 +260,65 @@ pub struct JwtToken {
     pub uid: i32,
     /// current user role.
     pub is_admin: bool,
+    /// Expiration time as unix timestamp. Zero for tokens issued before expiration was introduced.
+    #[serde(default)]
+    pub exp: i64,
+    /// Issue time as unix timestamp. Zero for tokens issued before it was introduced.
+    #[serde(default)]
+    pub iat: i64,
+    /// Login session ID. Empty for tokens issued before sessions were introduced.
+    #[serde(default)]
+    pub jti: String,
+    /// Roles of current user, like "mall-moderator".
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    /// Permissions granted by the roles, like "mall.moderate".
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub permissions: Vec<String>,
+}
+
+impl JwtToken {
+    /// Access token of the session, expiring after `server.access_token_ttl` seconds.
+    pub fn new(user: &Person, session: uuid::Uuid, grants: Grants) -> Self {
+        let now = chrono::Utc::now().timestamp();
+        Self {
+            uid: user.uid,
+            is_admin: user.is_admin,
+            exp: now + CONFIG.server.access_token_ttl,
+            iat: now,
+            jti: session.to_string(),
+            roles: grants.roles,
+            permissions: grants.permissions,
+        }
+    }
+
+    /// Administrators have all roles.
+    pub fn has_role(&self, role: &str) -> bool {
+        self.is_admin || self.roles.iter().any(|r| r == role)
+    }
+
+    /// Administrators have all permissions.
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.is_admin || self.permissions.iter().any(|p| p == permission.as_ref())
+    }
+
+    /// Return Forbidden error if current user does not have the permission.
+    pub fn require(&self, permission: Permission) -> Result<()> {
+        if self.has_permission(permission) {
+            Ok(())
+        } else {
+            Err(ApiError::new(CommonError::Forbidden))
+        }
+    }
+
+    /// Like `require`, but the user may always operate on resources of its own.
+    pub fn require_self_or(&self, uid: i32, permission: Permission) -> Result<()> {
+        if self.uid == uid {
+            Ok(())
+        } else {
+            self.require(permission)
+        }
+    }
 }
 
 fn get_auth_bearer_value(auth_string: &HeaderValue) -> Option<&str> {
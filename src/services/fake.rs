@@ -0,0 +1,88 @@
+//! App state for handler tests, with agents from `bridge::fake` and the example config.
+//!
+//! The database pool connects lazily, so handlers which do not touch the database are tested without one.
+//! Tests of the others are ignored by default. Run them with `cargo test -- --ignored` and `KITE_TEST_DB`
+//! pointing to a database with the production schema, like "postgresql://postgres@127.0.0.1/kite_test".
+
+use std::sync::Once;
+
+use actix_web::web;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use wechat_sdk::client::WeChatClientBuilder;
+
+use crate::bridge::AgentManager;
+use crate::config::{CONFIG, CONFIG_PATH_ENV};
+use crate::crypto;
+use crate::jwt::encode_jwt;
+use crate::models::user::{Permission, Person, SessionRegistry};
+
+use super::{AppState, JwtToken};
+
+/// Load kite.example.toml as the global config, which tokens in tests are signed with and secrets are
+/// encrypted with.
+pub fn load_config() {
+    static LOAD: Once = Once::new();
+
+    LOAD.call_once(|| {
+        std::env::set_var(
+            CONFIG_PATH_ENV,
+            concat!(env!("CARGO_MANIFEST_DIR"), "/kite.example.toml"),
+        );
+        lazy_static::initialize(&CONFIG);
+    });
+}
+
+/// Pool of the test database, which connects on first use.
+pub fn test_pool() -> PgPool {
+    let db = std::env::var("KITE_TEST_DB").unwrap_or_else(|_| "postgresql://localhost/kite".to_string());
+    PgPoolOptions::new().max_connections(2).connect_lazy(&db).unwrap()
+}
+
+pub fn app_state(agents: AgentManager) -> web::Data<AppState> {
+    load_config();
+    web::Data::new(AppState {
+        pool: test_pool(),
+        agents,
+        sessions: SessionRegistry::default(),
+        wx_client: WeChatClientBuilder::new().appid("test").secret("test").build(),
+    })
+}
+
+/// Authorization header of the user.
+pub fn bearer(uid: i32) -> String {
+    bearer_with(uid, &[])
+}
+
+/// Authorization header of the user granted the permissions.
+pub fn bearer_with(uid: i32, permissions: &[Permission]) -> String {
+    load_config();
+    let token = JwtToken {
+        uid,
+        is_admin: false,
+        exp: chrono::Utc::now().timestamp() + 600,
+        iat: chrono::Utc::now().timestamp(),
+        jti: String::new(),
+        roles: Vec::new(),
+        permissions: permissions.iter().map(|p| p.as_ref().to_string()).collect(),
+    };
+    format!("Bearer {}", encode_jwt(&token).unwrap())
+}
+
+/// Register a user bound to the student, with the OA secret encrypted as stored by `set_identity`.
+/// Return the uid.
+pub async fn register_student(pool: &PgPool, student_id: &str, oa_secret: &str) -> i32 {
+    let mut person = Person::default();
+    person.register(pool).await.unwrap();
+
+    sqlx::query(
+        "INSERT INTO public.identity (uid, student_id, oa_secret, oa_certified)
+            VALUES ($1, $2, $3, true)",
+    )
+    .bind(person.uid)
+    .bind(student_id)
+    .bind(crypto::encrypt(oa_secret).unwrap())
+    .execute(pool)
+    .await
+    .unwrap();
+    person.uid
+}
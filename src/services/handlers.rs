@@ -1,4 +1,6 @@
+pub mod agent;
 pub mod attachment;
+pub mod audit;
 pub mod contact;
 pub mod edu;
 pub mod event;
@@ -12,3 +14,65 @@ pub mod search;
 pub mod status;
 pub mod user;
 pub mod weather;
+
+#[cfg(test)]
+mod test {
+    use actix_web::http::Method;
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    use crate::bridge::fake::start_host;
+    use crate::models::CommonError;
+    use crate::services::fake::app_state;
+
+    use super::*;
+
+    #[tokio::test]
+    pub async fn login_needed() {
+        let (agents, _) = start_host("").await;
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state(agents))
+                .service(audit::query_audit_log)
+                .service(edu::query_score)
+                .service(event::delete_event)
+                .service(pay::fetch_expense)
+                .service(search::search)
+                .service(user::list_users)
+                .service(user::get_authentication_history)
+                .service(user::list_authentications)
+                .service(user::remove_authentication)
+                .service(user::get_user_identity)
+                .service(user::get_user_detail),
+        )
+        .await;
+
+        let routes = [
+            (Method::GET, "/audit"),
+            (Method::GET, "/edu/score?year=2021-2022&semester=1"),
+            (Method::DELETE, "/event/1"),
+            (Method::POST, "/pay/expense/fetch?mode=2"),
+            (Method::GET, "/search/notice/?query=kite"),
+            (Method::GET, "/user"),
+            (Method::GET, "/user/10/authentication/history"),
+            (Method::GET, "/user/10/authentication"),
+            (Method::DELETE, "/user/10/authentication/0"),
+            (Method::GET, "/user/10/identity"),
+            (Method::GET, "/user/10"),
+        ];
+        for (method, uri) in routes.iter() {
+            let request = test::TestRequest::default()
+                .method(method.clone())
+                .uri(uri)
+                .to_request();
+            let response: Value = test::read_response_json(&app, request).await;
+            assert_eq!(
+                response["code"],
+                CommonError::LoginNeeded as u16,
+                "{} {}",
+                method,
+                uri
+            );
+        }
+    }
+}
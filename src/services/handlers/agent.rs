@@ -0,0 +1,42 @@
+use actix_http::ws::{hash_key, verify_handshake};
+use actix_web::http::{header, HeaderValue, StatusCode};
+use actix_web::{get, web, HttpRequest, HttpResponse};
+
+use crate::bridge::websocket;
+use crate::config::CONFIG;
+use crate::error::{ApiError, Result};
+use crate::models::CommonError;
+use crate::services::AppState;
+
+/// WebSocket endpoint for agents which can not reach the agent port directly.
+/// Agents are authenticated by the handshake on the connection, rather than a JWT token.
+#[get("/agent/ws")]
+pub async fn connect_agent(
+    req: HttpRequest,
+    payload: web::Payload,
+    app: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if !CONFIG.host.websocket {
+        return Err(ApiError::new(CommonError::Forbidden));
+    }
+    verify_handshake(req.head()).map_err(|_| ApiError::new(CommonError::Parameter))?;
+
+    let key = req
+        .headers()
+        .get(header::SEC_WEBSOCKET_KEY)
+        .map(|key| hash_key(key.as_bytes()))
+        .ok_or_else(|| ApiError::new(CommonError::Parameter))?;
+    let accept = HeaderValue::from_bytes(&key).map_err(|_| ApiError::new(CommonError::Parameter))?;
+    let source_addr = req
+        .connection_info()
+        .realip_remote_addr()
+        .unwrap_or_default()
+        .to_string();
+
+    let outgoing = websocket::accept(&app.agents, payload, source_addr);
+    let response = HttpResponse::build(StatusCode::SWITCHING_PROTOCOLS)
+        .upgrade("websocket")
+        .insert_header((header::SEC_WEBSOCKET_ACCEPT, accept))
+        .streaming(outgoing);
+    Ok(response)
+}
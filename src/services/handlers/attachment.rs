@@ -4,8 +4,10 @@ use tokio::io::AsyncWriteExt;
 
 use crate::config::CONFIG;
 use crate::error::{ApiError, Result};
+use crate::models::audit;
 use crate::models::file::{get_attachment_url_prefix, get_file_extension};
 use crate::models::file::{Attachment, AttachmentBasic, AttachmentError, AttachmentManager};
+use crate::models::user::Permission;
 use crate::models::{CommonError, PageView};
 use crate::services::{response::ApiResponse, AppState, JwtToken};
 
@@ -95,10 +97,9 @@ pub async fn list_attachments(
     page: web::Query<PageView>,
 ) -> Result<HttpResponse> {
     let token = token.ok_or_else(|| ApiError::new(CommonError::Forbidden))?;
-    if !token.is_admin {
-        return Err(ApiError::new(CommonError::Forbidden));
-    }
+    token.require(Permission::ManageAttachment)?;
     let attachments = AttachmentManager::new(&app.pool).list(page.into_inner()).await?;
+    audit::record(&app.pool, token.uid, "attachment.list", "attachment", None).await?;
     Ok(HttpResponse::Ok().json(&ApiResponse::normal(attachments)))
 }
 
@@ -108,9 +109,12 @@ pub async fn query_attachment(
     token: Option<JwtToken>,
     id: web::Path<(uuid::Uuid,)>,
 ) -> Result<HttpResponse> {
-    let attachment = AttachmentManager::new(&app.pool).query(id.into_inner().0).await?;
+    let id = id.into_inner().0;
+    let attachment = AttachmentManager::new(&app.pool).query(id).await?;
     if let Some(token) = token {
-        if token.is_admin {
+        if token.has_permission(Permission::ManageAttachment) {
+            let target = format!("attachment:{}", id);
+            audit::record(&app.pool, token.uid, "attachment.get", &target, None).await?;
             return Ok(HttpResponse::Ok().json(&ApiResponse::normal(attachment)));
         }
     }
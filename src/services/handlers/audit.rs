@@ -0,0 +1,24 @@
+use actix_web::{get, web, HttpResponse};
+
+use crate::error::{ApiError, Result};
+use crate::models::audit::{self, AuditFilter};
+use crate::models::user::Permission;
+use crate::models::{CommonError, PageView};
+use crate::services::response::ApiResponse;
+use crate::services::{AppState, JwtToken};
+
+/// Query the audit log of privileged actions, latest first.
+#[get("/audit")]
+pub async fn query_audit_log(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    filter: web::Query<AuditFilter>,
+    page: web::Query<PageView>,
+) -> Result<HttpResponse> {
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
+    token.require(Permission::ViewAudit)?;
+
+    let records = audit::query(&app.pool, &filter, &page).await?;
+    audit::record(&app.pool, token.uid, "audit.query", "audit", None).await?;
+    Ok(HttpResponse::Ok().json(&ApiResponse::normal(records)))
+}
@@ -9,6 +9,7 @@ use crate::bridge::{
     RequestFrame, RequestPayload, ResponsePayload, SchoolYear, ScoreDetailRequest, ScoreRequest,
     TimeTableRequest,
 };
+use crate::crypto;
 use crate::error::{ApiError, Result};
 use crate::models::edu::{
     self, get_save_score, get_score, get_score_detail, save_detail, save_score, AvailClassroomQuery,
@@ -90,7 +91,7 @@ pub async fn query_timetable(
 
     let data = TimeTableRequest {
         account: identity.student_id,
-        passwd: identity.oa_secret,
+        passwd: crypto::decrypt(&identity.oa_secret)?,
         school_year: year,
         semester,
     };
@@ -170,7 +171,7 @@ pub async fn export_timetable_as_calendar(
 
     let data = TimeTableRequest {
         account: identity.student_id,
-        passwd: identity.oa_secret,
+        passwd: crypto::decrypt(&identity.oa_secret)?,
         school_year: year,
         semester,
     };
@@ -211,7 +212,7 @@ pub async fn query_score(
         .ok_or_else(|| ApiError::new(CommonError::IdentityNeeded))?;
 
     let account = identity.student_id;
-    let password = identity.oa_secret;
+    let password = crypto::decrypt(&identity.oa_secret)?;
 
     let params = params.into_inner();
 
@@ -297,7 +298,7 @@ pub async fn query_score_detail(
 
     let data = ScoreDetailRequest {
         account: identity.student_id,
-        password: identity.oa_secret,
+        password: crypto::decrypt(&identity.oa_secret)?,
         school_year: year,
         semester,
         class_id: params.class_id.clone(),
@@ -447,7 +448,7 @@ pub async fn get_exam_arrangement(
         .await?
         .ok_or_else(|| ApiError::new(CommonError::IdentityNeeded))?;
     let account = identity.student_id;
-    let password = identity.oa_secret;
+    let password = crypto::decrypt(&identity.oa_secret)?;
 
     let params = params.into_inner();
     let semester = trans_to_semester(params.semester as i32);
@@ -472,3 +473,66 @@ pub async fn get_exam_arrangement(
         Err(ApiError::new(HostError::Mismatched))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    use crate::bridge::fake::{start_host, wait_agents, FakeAgent};
+    use crate::bridge::{
+        ErrorResponse, PortalAuthResponse, RequestPayload, ResponsePayload, SchoolYear, Score, Semester,
+    };
+    use crate::services::fake::{app_state, bearer, register_student};
+
+    #[tokio::test]
+    #[ignore = "needs KITE_TEST_DB"]
+    pub async fn query_score() {
+        let (agents, addr) = start_host("").await;
+        FakeAgent::new("agent-1")
+            .on("PortalAuth", |payload| match payload {
+                // Only the portal login carries the OA secret, decrypted before sent to agents.
+                RequestPayload::PortalAuth(request) if request.credential == "oa-secret" => Ok(
+                    ResponsePayload::PortalAuth(PortalAuthResponse::Session("session".to_string())),
+                ),
+                _ => Err(ErrorResponse {
+                    code: 10,
+                    msg: "wrong credential".to_string(),
+                }),
+            })
+            .on("Score", |payload| match payload {
+                RequestPayload::Score(request)
+                    if matches!(request.school_year, SchoolYear::SomeYear(2021))
+                        && matches!(request.semester, Semester::FirstTerm) =>
+                {
+                    Ok(ResponsePayload::Score(vec![Score {
+                        score: 90.0,
+                        course: "Rust".to_string(),
+                        course_id: "R001".to_string(),
+                        class_id: "R001.01".to_string(),
+                        school_year: "2021-2022".to_string(),
+                        semester: 1,
+                        credit: 2.0,
+                    }]))
+                }
+                _ => Ok(ResponsePayload::Score(Vec::new())),
+            })
+            .connect(&addr)
+            .await
+            .unwrap();
+        assert!(wait_agents(&agents, 1).await);
+
+        let app_state = app_state(agents);
+        let student_id = format!("19{:08}", rand::random::<u32>() % 100_000_000);
+        let uid = register_student(&app_state.pool, &student_id, "oa-secret").await;
+        let app = test::init_service(App::new().app_data(app_state).service(super::query_score)).await;
+
+        let request = test::TestRequest::get()
+            .uri("/edu/score?year=2021-2022&semester=1")
+            .insert_header(("Authorization", bearer(uid)))
+            .to_request();
+        let response: Value = test::read_response_json(&app, request).await;
+        assert_eq!(response["code"], 0);
+        assert_eq!(response["data"]["score"][0]["course"], "Rust");
+    }
+}
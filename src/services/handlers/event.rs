@@ -1,5 +1,5 @@
 //! This module includes interfaces about the event and sign.
-use actix_web::{get, post, web, HttpResponse};
+use actix_web::{delete, get, post, web, HttpResponse};
 use serde::Deserialize;
 use serde_json::json;
 use sqlx::PgPool;
@@ -8,6 +8,7 @@ use crate::bridge::{
     ActivityDetailRequest, AgentManager, HostError, RequestFrame, RequestPayload, ResponsePayload,
     SaveScActivity, SaveScScore, ScActivityRequest, ScJoinRequest, ScScoreItemRequest, ScScoreSummary,
 };
+use crate::crypto;
 use crate::error::{ApiError, Result};
 use crate::models::edu::{
     get_sc_score_detail, query_activity_detail, query_current_sc_activity_list,
@@ -17,17 +18,18 @@ use crate::models::event::{
     get_sc_activity_detail, get_sc_activity_list, query_sc_score, Event, EventError, ScScore,
 };
 use crate::models::sc::{delete_sc_score_list, save_image, save_image_as_file};
-use crate::models::user::Person;
-use crate::models::{event, CommonError, PageView};
+use crate::models::user::{Permission, Person};
+use crate::models::{audit, event, CommonError, PageView};
 use crate::services::response::ApiResponse;
 use crate::services::{AppState, JwtToken};
 
 /**********************************************************************
     Interfaces in this module:
     list_events()         <-- get  /event
+    create_event()        <-- post /event
+    delete_event()        <-- delete /event/{event_id}
 
     // TODO: implementing.
-    create_event()        <-- post /event
     get_event()           <-- get  /event/{event_id}
     get_participants()    <-- get  /event/{event_id}/participant
     participate()         <-- post /event/{event_id}/participant
@@ -50,12 +52,10 @@ pub async fn create_event(
     form: web::Form<Event>,
 ) -> Result<HttpResponse> {
     //User need log in before create event
-    if token.is_none() {
-        return Err(ApiError::new(CommonError::LoginNeeded));
-    }
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
 
     //User need identity before create event
-    let uid = token.unwrap().uid;
+    let uid = token.uid;
     if Person::get_identity(&app.pool, uid).await?.is_none() {
         return Err(ApiError::new(EventError::NeedIdentity));
     }
@@ -63,7 +63,8 @@ pub async fn create_event(
     let parameter: Event = form.into_inner();
     let mut event: Event = Event::new();
 
-    event.publisher_uid = parameter.publisher_uid;
+    // The publisher is who creates the event, rather than anyone the form claims.
+    event.publisher_uid = Some(uid);
     event.description = parameter.description;
     event.title = parameter.title;
     event.start_time = parameter.start_time;
@@ -77,6 +78,32 @@ pub async fn create_event(
     Ok(HttpResponse::Ok().json(ApiResponse::normal(event)))
 }
 
+#[delete("/event/{event_id}")]
+pub async fn delete_event(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    event_id: web::Path<i32>,
+) -> Result<HttpResponse> {
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
+    let event_id = event_id.into_inner();
+
+    // Only the publisher and event managers can delete it.
+    let publisher = Event::get_publisher(&app.pool, event_id)
+        .await?
+        .ok_or_else(|| ApiError::new(EventError::NoSuchEvent))?;
+    token.require_self_or(publisher, Permission::ManageEvent)?;
+
+    let mut tx = app.pool.begin().await?;
+    Event::delete(&mut tx, event_id).await?;
+    if publisher != token.uid {
+        let target = format!("event:{}", event_id);
+        audit::record(&mut tx, token.uid, "event.delete", &target, None).await?;
+    }
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::empty()))
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ScDetailQuery {
     pub force: bool,
@@ -95,7 +122,7 @@ pub async fn get_sc_score_list(
         .await?
         .ok_or_else(|| ApiError::new(CommonError::IdentityNeeded))?;
     let account = identity.student_id;
-    let password = identity.oa_secret;
+    let password = crypto::decrypt(&identity.oa_secret)?;
     let params = params.into_inner();
 
     if params.force {
@@ -321,7 +348,7 @@ pub async fn apply_sc_event_activity(
 
     let data = ScJoinRequest {
         account: identity.student_id,
-        password: identity.oa_secret,
+        password: crypto::decrypt(&identity.oa_secret)?,
         activity_id: activity_id.into_inner(),
         force: params.force,
     };
@@ -338,3 +365,58 @@ pub async fn apply_sc_event_activity(
         Err(ApiError::new(HostError::Mismatched))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    use crate::bridge::fake::start_host;
+    use crate::models::audit::{self, AuditFilter};
+    use crate::models::event::{Event, EventError};
+    use crate::models::user::{Permission, Person};
+    use crate::models::{CommonError, PageView};
+    use crate::services::fake::{app_state, bearer, bearer_with};
+
+    #[tokio::test]
+    #[ignore = "needs KITE_TEST_DB"]
+    pub async fn delete_event() {
+        let (agents, _) = start_host("").await;
+        let app_state = app_state(agents);
+        let pool = app_state.pool.clone();
+        let mut publisher = Person::default();
+        publisher.register(&pool).await.unwrap();
+        let mut event = Event::new();
+        event.publisher_uid = Some(publisher.uid);
+        event.title = "Rust meetup".to_string();
+        event.create(&pool).await.unwrap();
+        let app = test::init_service(App::new().app_data(app_state).service(super::delete_event)).await;
+
+        let uri = format!("/event/{}", event.id);
+        let delete_as = |bearer: String| {
+            test::TestRequest::delete()
+                .uri(&uri)
+                .insert_header(("Authorization", bearer))
+                .to_request()
+        };
+        // Others need the permission of event managers.
+        let other = publisher.uid + 1;
+        let response: Value = test::read_response_json(&app, delete_as(bearer(other))).await;
+        assert_eq!(response["code"], CommonError::Forbidden as u16);
+
+        let manager = bearer_with(other, &[Permission::ManageEvent]);
+        let response: Value = test::read_response_json(&app, delete_as(manager)).await;
+        assert_eq!(response["code"], 0);
+
+        let response: Value = test::read_response_json(&app, delete_as(bearer(publisher.uid))).await;
+        assert_eq!(response["code"], EventError::NoSuchEvent as u16);
+
+        let filter = AuditFilter {
+            target: Some(format!("event:{}", event.id)),
+            ..AuditFilter::default()
+        };
+        let records = audit::query(&pool, &filter, &PageView::default()).await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].actor, other);
+    }
+}
@@ -2,14 +2,38 @@
 use actix_web::{get, post, put, web, HttpResponse};
 use serde::Deserialize;
 
-use crate::error::Result;
-use crate::models::freshman::FreshmanManager;
+use crate::error::{ApiError, Result};
+use crate::models::audit;
+use crate::models::freshman::{FreshmanBasic, FreshmanError, FreshmanManager};
+use crate::models::user::Permission;
 use crate::models::CommonError;
 use crate::services::{response::ApiResponse, AppState, JwtToken};
 
 #[derive(Debug, Deserialize)]
 pub struct FreshmanReqSecret {
-    pub secret: String,
+    /// Secret of the freshman, which freshman operators may omit.
+    pub secret: Option<String>,
+}
+
+/// Query the freshman with the secret. Freshman operators can look up by student id or ticket number without
+/// it, and each such lookup is audited.
+async fn query_freshman(
+    app: &AppState,
+    token: &JwtToken,
+    account: &str,
+    secret: Option<String>,
+) -> Result<FreshmanBasic> {
+    let manager = FreshmanManager::new(&app.pool);
+    match secret {
+        Some(secret) => manager.query(account, &secret).await,
+        None if token.has_permission(Permission::ManageFreshman) => {
+            let freshman = manager.find(account).await?;
+            let target = format!("freshman:{}", freshman.student_id);
+            audit::record(&app.pool, token.uid, "freshman.view", &target, None).await?;
+            Ok(freshman)
+        }
+        None => Err(ApiError::new(FreshmanError::SecretNeeded)),
+    }
 }
 
 #[get("/freshman/{account}")]
@@ -19,7 +43,7 @@ pub async fn get_basic_info(
     path: web::Path<String>,
     form: web::Query<FreshmanReqSecret>,
 ) -> Result<HttpResponse> {
-    let token = token.unwrap();
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
     let parameters: FreshmanReqSecret = form.into_inner();
     let account = path.into_inner();
     let secret = parameters.secret;
@@ -27,9 +51,11 @@ pub async fn get_basic_info(
     if account.is_empty() {
         return Err(CommonError::Parameter.into());
     }
+    // Only the freshman knowing the secret binds the account, rather than the operator looking it up.
+    let bind = secret.is_some();
+    let freshman = query_freshman(&app, &token, &account, secret).await?;
     let manager = FreshmanManager::new(&app.pool);
-    let freshman = manager.query(&account, secret.as_str()).await?;
-    if freshman.uid.is_none() && !manager.is_bound(token.uid).await? {
+    if bind && freshman.uid.is_none() && !manager.is_bound(token.uid).await? {
         manager.bind(&freshman.student_id, Some(token.uid)).await?;
     }
     Ok(HttpResponse::Ok().json(ApiResponse::normal(freshman)))
@@ -49,7 +75,7 @@ pub async fn update_account(
     path: web::Path<String>,
     form: web::Form<UpdateInfo>,
 ) -> Result<HttpResponse> {
-    let _ = token.unwrap();
+    let _ = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
     let account = path.into_inner();
     let form = form.into_inner();
     let secret = form.secret;
@@ -78,13 +104,11 @@ pub async fn get_roommate(
     path: web::Path<String>,
     secret: web::Query<FreshmanReqSecret>,
 ) -> Result<HttpResponse> {
-    let _ = token.unwrap();
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
     let account = path.into_inner();
     let secret = secret.into_inner().secret;
 
-    let freshman_manager = FreshmanManager::new(&app.pool);
-    let roommates = freshman_manager
-        .query(&account, &secret)
+    let roommates = query_freshman(&app, &token, &account, secret)
         .await?
         .get_roommates(&app.pool)
         .await?;
@@ -102,13 +126,11 @@ pub async fn get_people_familiar(
     path: web::Path<String>,
     secret: web::Query<FreshmanReqSecret>,
 ) -> Result<HttpResponse> {
-    let _ = token.unwrap();
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
     let account = path.into_inner();
     let secret = secret.into_inner().secret;
 
-    let freshman_manager = FreshmanManager::new(&app.pool);
-    let people_familiar = freshman_manager
-        .query(&account, &secret)
+    let people_familiar = query_freshman(&app, &token, &account, secret)
         .await?
         .get_people_familiar(&app.pool)
         .await?;
@@ -125,13 +147,11 @@ pub async fn get_classmate(
     path: web::Path<String>,
     secret: web::Query<FreshmanReqSecret>,
 ) -> Result<HttpResponse> {
-    let _ = token.unwrap();
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
     let account = path.into_inner();
     let secret = secret.into_inner().secret;
 
-    let freshman_manager = FreshmanManager::new(&app.pool);
-    let classmates = freshman_manager
-        .query(&account, &secret)
+    let classmates = query_freshman(&app, &token, &account, secret)
         .await?
         .get_classmates(&app.pool)
         .await?;
@@ -148,13 +168,11 @@ pub async fn get_analysis_data(
     path: web::Path<String>,
     secret: web::Query<FreshmanReqSecret>,
 ) -> Result<HttpResponse> {
-    let _ = token.unwrap();
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
     let account = path.into_inner();
     let secret = secret.into_inner().secret;
 
-    let freshman_manager = FreshmanManager::new(&app.pool);
-    let freshman = freshman_manager
-        .query(&account, &secret)
+    let freshman = query_freshman(&app, &token, &account, secret)
         .await?
         .get_analysis(&app.pool)
         .await?;
@@ -171,7 +189,11 @@ pub async fn post_analysis_log(
     secret: web::Query<FreshmanReqSecret>,
 ) -> Result<HttpResponse> {
     let account = path.into_inner();
-    let secret = secret.into_inner().secret;
+    // Share logs count freshmen themselves, so the secret is always needed.
+    let secret = secret
+        .into_inner()
+        .secret
+        .ok_or_else(|| ApiError::new(FreshmanError::SecretNeeded))?;
 
     let freshman_manager = FreshmanManager::new(&app.pool);
     let freshman = freshman_manager.query(&account, &secret).await?;
@@ -116,3 +116,52 @@ pub async fn query_book_detail(
     // }
     Err(ApiError::new(CommonError::Forbidden))
 }
+
+#[cfg(test)]
+mod test {
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    use crate::bridge::fake::{start_host, wait_agents, FakeAgent};
+    use crate::bridge::{RequestPayload, ResponsePayload, SearchLibraryResult};
+    use crate::services::fake::app_state;
+
+    #[tokio::test]
+    pub async fn query_books() {
+        let (agents, addr) = start_host("").await;
+        FakeAgent::new("agent-1")
+            .on("SearchLibrary", |payload| match payload {
+                RequestPayload::SearchLibrary(request) if request.keyword == "rust" => {
+                    Ok(ResponsePayload::SearchLibrary(SearchLibraryResult {
+                        result_count: 0,
+                        use_time: 0.1,
+                        current_page: request.page,
+                        total_pages: 0,
+                        book_list: Vec::new(),
+                    }))
+                }
+                _ => Ok(ResponsePayload::Pong(String::new())),
+            })
+            .connect(&addr)
+            .await
+            .unwrap();
+        assert!(wait_agents(&agents, 1).await);
+
+        let app =
+            test::init_service(App::new().app_data(app_state(agents)).service(super::query_books)).await;
+
+        let request = test::TestRequest::get()
+            .uri("/library/book?keyword=rust&index=2")
+            .to_request();
+        let response: Value = test::read_response_json(&app, request).await;
+        assert_eq!(response["code"], 0);
+        assert_eq!(response["data"]["currentPage"], 2);
+
+        // Responses of another kind are reported as mismatched.
+        let request = test::TestRequest::get()
+            .uri("/library/book?keyword=go")
+            .to_request();
+        let response: Value = test::read_response_json(&app, request).await;
+        assert_ne!(response["code"], 0);
+    }
+}
@@ -2,10 +2,11 @@ use actix_web::{delete, get, post, put, web, HttpResponse};
 use wechat_sdk::wechat::Check;
 
 use crate::error::{ApiError, Result};
+use crate::models::audit;
 use crate::models::mall::{
     self, Comment, CommentUni, MallError, PubComment, PubWish, SelectGoods, UpdateGoods,
 };
-use crate::models::user;
+use crate::models::user::{self, Permission};
 use crate::models::{CommonError, PageView};
 use crate::services::response::ApiResponse;
 use crate::services::{AppState, JwtToken};
@@ -119,15 +120,14 @@ pub async fn get_goods_by_id(
     item_code: web::Path<String>,
 ) -> Result<HttpResponse> {
     let item_code = item_code.into_inner();
-    let uid = token
-        .map(|token| token.uid)
-        .ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
 
     //获取商品详情
     let detail = mall::get_goods_detail(&app.pool, &item_code).await?;
 
-    //插入观看日志
-    mall::insert_view_log(&app.pool, uid, &item_code).await?;
+    //插入观看日志，未登录用户不记录
+    if let Some(token) = token {
+        mall::insert_view_log(&app.pool, token.uid, &item_code).await?;
+    }
 
     let response = serde_json::json!({
         "detail": detail,
@@ -223,11 +223,25 @@ pub async fn update_goods(
 #[delete("/mall/goods/{pub_code}")]
 pub async fn delete_goods(
     app: web::Data<AppState>,
+    token: Option<JwtToken>,
     pub_code: web::Path<String>,
 ) -> Result<HttpResponse> {
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
     let pub_code = pub_code.into_inner();
 
-    let _ = mall::delete_goods(&app.pool, &pub_code).await?;
+    // 仅发布者和商城管理员可删除
+    let publisher = mall::get_goods_publisher(&app.pool, &pub_code).await?;
+    if let Some(publisher) = publisher {
+        token.require_self_or(publisher, Permission::ModerateMall)?;
+    }
+
+    let mut tx = app.pool.begin().await?;
+    let _ = mall::delete_goods(&mut tx, &pub_code).await?;
+    if matches!(publisher, Some(publisher) if publisher != token.uid) {
+        let target = format!("goods:{}", pub_code);
+        audit::record(&mut tx, token.uid, "mall.goods.delete", &target, None).await?;
+    }
+    tx.commit().await?;
 
     Ok(HttpResponse::Ok().json(&ApiResponse::empty()))
 }
@@ -271,9 +285,24 @@ pub async fn publish_comment(
 #[delete("/mall/comment/{com_code}")]
 pub async fn delete_comment(
     app: web::Data<AppState>,
+    token: Option<JwtToken>,
     com_code: web::Path<String>,
 ) -> Result<HttpResponse> {
-    let _ = mall::delete_comment(&app.pool, com_code.into_inner()).await?;
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
+    let com_code = com_code.into_inner();
+
+    // 仅评论者和商城管理员可删除
+    let author = mall::get_comment_author(&app.pool, &com_code).await?;
+    if let Some(author) = author {
+        token.require_self_or(author, Permission::ModerateMall)?;
+    }
+    let target = format!("comment:{}", com_code);
+    let mut tx = app.pool.begin().await?;
+    let _ = mall::delete_comment(&mut tx, com_code).await?;
+    if matches!(author, Some(author) if author != token.uid) {
+        audit::record(&mut tx, token.uid, "mall.comment.delete", &target, None).await?;
+    }
+    tx.commit().await?;
     Ok(HttpResponse::Ok().json(&ApiResponse::empty()))
 }
 
@@ -6,6 +6,7 @@ use chrono::{DateTime, Duration, FixedOffset, Local, TimeZone};
 use sqlx::PgPool;
 
 use crate::bridge::{AgentManager, ExpenseRequest};
+use crate::crypto;
 use crate::error::ApiError;
 use crate::error::Result;
 use crate::models::pay::BalanceManager;
@@ -138,11 +139,12 @@ pub async fn fetch_all_expense_records(
 }
 
 pub async fn fetch_expense_in_parallel(identity: Identity, app: web::Data<AppState>) -> Result<()> {
+    let password = crypto::decrypt(&identity.oa_secret)?;
     tokio::spawn(async move {
         let pool = app.pool.clone();
-        let agents = app.agents.clone();
+        let agents = app.agents.background();
 
-        match fetch_all_expense_records(pool, agents, &identity.student_id, &identity.oa_secret).await {
+        match fetch_all_expense_records(pool, agents, &identity.student_id, &password).await {
             Ok(_) => (),
             Err(e) => println!("Fetch all expense records error: {:?}", e),
         }
@@ -159,13 +161,13 @@ pub async fn fetch_expense_in_iteration(identity: Identity, app: web::Data<AppSt
 pub async fn fetch_expense_iteratively(identity: Identity, app: web::Data<AppState>) -> Result<()> {
     use crate::models::pay::{query_last_record_ts, request_expense_page, save_expense_record};
 
-    let agents = &app.agents;
+    let agents = app.agents.background();
     let pool = &app.pool;
 
     let end_time = chrono::Local::today();
     let mut expense_request = ExpenseRequest {
         account: identity.student_id,
-        password: identity.oa_secret,
+        password: crypto::decrypt(&identity.oa_secret)?,
         page: Some(1),
         start_time: Some("20211001".to_string()),
         end_time: Some(end_time.format("%Y%m%d").to_string()),
@@ -272,3 +274,58 @@ pub async fn query_expense(
     .await?;
     Ok(HttpResponse::Ok().json(ApiResponse::normal(serde_json::json!({ "records": records }))))
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use actix_web::{test, App};
+    use serde_json::Value;
+    use tokio::sync::mpsc;
+
+    use crate::bridge::fake::{start_host, wait_agents, FakeAgent};
+    use crate::bridge::{ExpensePage, PageInfo, PortalAuthResponse, RequestPayload, ResponsePayload};
+    use crate::services::fake::{app_state, bearer, register_student};
+
+    #[tokio::test]
+    #[ignore = "needs KITE_TEST_DB"]
+    pub async fn fetch_expense() {
+        let (agents, addr) = start_host("").await;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        FakeAgent::new("agent-1")
+            .on("PortalAuth", move |payload| {
+                if let RequestPayload::PortalAuth(request) = payload {
+                    tx.send(request.credential.clone()).unwrap();
+                }
+                Ok(ResponsePayload::PortalAuth(PortalAuthResponse::Session(
+                    "session".to_string(),
+                )))
+            })
+            .on("CardExpense", |_| {
+                Ok(ResponsePayload::CardExpense(ExpensePage {
+                    records: Vec::new(),
+                    page: PageInfo { current: 1, total: 1 },
+                }))
+            })
+            .connect(&addr)
+            .await
+            .unwrap();
+        assert!(wait_agents(&agents, 1).await);
+
+        let app_state = app_state(agents);
+        let student_id = format!("19{:08}", rand::random::<u32>() % 100_000_000);
+        let uid = register_student(&app_state.pool, &student_id, "oa-secret").await;
+        let app = test::init_service(App::new().app_data(app_state).service(super::fetch_expense)).await;
+
+        let request = test::TestRequest::post()
+            .uri("/pay/expense/fetch?mode=2")
+            .insert_header(("Authorization", bearer(uid)))
+            .to_request();
+        let response: Value = test::read_response_json(&app, request).await;
+        assert_eq!(response["code"], 0);
+
+        // Records are fetched in background, after logging in to the portal with the decrypted OA secret.
+        let credential = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await;
+        assert_eq!(credential.unwrap().unwrap(), "oa-secret");
+    }
+}
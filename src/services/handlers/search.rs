@@ -22,11 +22,10 @@ pub async fn search(
     let query = query.into_inner().query;
 
     //User need log in before search
-    if token.is_none() {
-        return Err(ApiError::new(CommonError::LoginNeeded));
-    }
+    let uid = token
+        .ok_or_else(|| ApiError::new(CommonError::LoginNeeded))
+        .map(|token| token.uid)?;
     //User need identity before search
-    let uid = token.unwrap().uid;
     if Person::get_identity(&app.pool, uid).await?.is_none() {
         return Err(ApiError::new(SearchError::NeedIdentity));
     }
@@ -1,9 +1,11 @@
-use actix_web::{get, web, HttpResponse};
+use actix_web::{delete, get, web, HttpResponse};
 use chrono::Local;
 use serde::Deserialize;
 
 use crate::bridge::{HostError, RequestFrame, RequestPayload, ResponsePayload};
 use crate::error::{ApiError, Result};
+use crate::models::audit;
+use crate::models::user::Permission;
 use crate::models::CommonError;
 use crate::services::response::ApiResponse;
 use crate::services::{AppState, JwtToken};
@@ -21,14 +23,36 @@ pub async fn get_timestamp() -> Result<HttpResponse> {
 #[get("/status/agent")]
 pub async fn get_agent_list(app: web::Data<AppState>, token: Option<JwtToken>) -> Result<HttpResponse> {
     let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
-    if !token.is_admin {
-        return Err(CommonError::Forbidden.into());
-    }
+    token.require(Permission::ManageAgent)?;
 
     let agents = &app.agents;
     let response = serde_json::json!({
         "agents": agents.get_client_list().await,
     });
+    audit::record(&app.pool, token.uid, "agent.list", "agent", None).await?;
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(response)))
+}
+
+#[delete("/status/agent/cache")]
+pub async fn purge_agent_cache(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+) -> Result<HttpResponse> {
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
+    token.require(Permission::ManageAgent)?;
+
+    let purged = app.agents.purge_cache().await;
+    let response = serde_json::json!({
+        "purged": purged,
+    });
+    audit::record(
+        &app.pool,
+        token.uid,
+        "agent.cache.purge",
+        "agent",
+        Some(response.clone()),
+    )
+    .await?;
     Ok(HttpResponse::Ok().json(ApiResponse::normal(response)))
 }
 
@@ -56,3 +80,68 @@ pub async fn ping_agent(
         Err(ApiError::new(HostError::Mismatched))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    use crate::bridge::fake::{start_host, wait_agents, FakeAgent};
+    use crate::bridge::{
+        BookHoldingRequest, HoldingPreviews, RequestFrame, RequestPayload, ResponsePayload,
+    };
+    use crate::models::user::{Permission, Person};
+    use crate::services::fake::{app_state, bearer_with};
+
+    fn book_holding() -> RequestFrame {
+        RequestFrame::new(RequestPayload::BookHoldingInfo(BookHoldingRequest {
+            book_id_list: vec!["1".to_string()],
+        }))
+    }
+
+    #[tokio::test]
+    #[ignore = "needs KITE_TEST_DB"]
+    pub async fn purge_agent_cache() {
+        let (agents, addr) = start_host("[cache_ttl]\nBookHoldingInfo = 60").await;
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        FakeAgent::new("agent-1")
+            .on("BookHoldingInfo", move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(ResponsePayload::BookHoldingInfo(HoldingPreviews {
+                    holding_previews: HashMap::new(),
+                }))
+            })
+            .connect(&addr)
+            .await
+            .unwrap();
+        assert!(wait_agents(&agents, 1).await);
+        for _ in 0..2 {
+            agents.request(book_holding()).await.unwrap().unwrap();
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        let app_state = app_state(agents.clone());
+        let mut person = Person::default();
+        person.register(&app_state.pool).await.unwrap();
+        let app =
+            test::init_service(App::new().app_data(app_state).service(super::purge_agent_cache)).await;
+        let request = test::TestRequest::delete()
+            .uri("/status/agent/cache")
+            .insert_header((
+                "Authorization",
+                bearer_with(person.uid, &[Permission::ManageAgent]),
+            ))
+            .to_request();
+        let response: Value = test::read_response_json(&app, request).await;
+        assert_eq!(response["data"]["purged"], 1);
+
+        // The next request is sent to the agent again.
+        agents.request(book_holding()).await.unwrap().unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,11 +1,15 @@
 use crate::error::{ApiError, Result};
 use crate::jwt::encode_jwt;
+use crate::models::audit;
 use crate::models::file::AvatarManager;
-use crate::models::user::{get_default_avatar, Authentication, Identity, Person, UserError};
+use crate::models::user::{
+    add_role, get_default_avatar, remove_role, role_permissions, Authentication, Grants, Identity,
+    Permission, Person, RefreshToken, UserError, ADMIN_ROLE,
+};
 use crate::models::user::{LOGIN_BY_PASSWORD, LOGIN_BY_WECHAT};
-use crate::models::CommonError;
+use crate::models::{CommonError, PageView};
 use crate::services::{response::ApiResponse, AppState, JwtToken};
-use actix_web::{get, post, put, web, HttpResponse};
+use actix_web::{delete, get, post, put, web, HttpResponse};
 use serde::Deserialize;
 use wechat_sdk::wechat::{Login, WxSession};
 
@@ -57,17 +61,151 @@ pub async fn login(app: web::Data<AppState>, form: web::Form<AuthParameters>) ->
         return Err(ApiError::new(UserError::Disabled));
     }
 
-    let token = encode_jwt(&JwtToken {
-        uid: user.uid,
-        is_admin: user.is_admin,
-    })?;
+    let session = app.sessions.create(&app.pool, user.uid).await?;
+    let grants = Grants::of(&app.pool, user.uid).await?;
+    let token = encode_jwt(&JwtToken::new(&user, session, grants))?;
+    let refresh_token = RefreshToken::issue(&app.pool, user.uid, session).await?;
     let response = serde_json::json!({
         "token": token,
+        "refreshToken": refresh_token,
         "data": user,
     });
     Ok(HttpResponse::Ok().json(&ApiResponse::normal(response)))
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RefreshParameters {
+    pub refresh_token: String,
+}
+
+/// Exchange the refresh token for a new access token and a new refresh token.
+#[post("/session/refresh")]
+pub async fn refresh_session(
+    app: web::Data<AppState>,
+    form: web::Form<RefreshParameters>,
+) -> Result<HttpResponse> {
+    let (uid, session, refresh_token) =
+        RefreshToken::rotate(&app.pool, &app.sessions, &form.refresh_token).await?;
+    let user = Person::get(&app.pool, uid).await?;
+    if user.is_disabled {
+        return Err(ApiError::new(UserError::Disabled));
+    }
+
+    let grants = Grants::of(&app.pool, user.uid).await?;
+    let response = serde_json::json!({
+        "token": encode_jwt(&JwtToken::new(&user, session, grants))?,
+        "refreshToken": refresh_token,
+    });
+    Ok(HttpResponse::Ok().json(&ApiResponse::normal(response)))
+}
+
+/// Log out, revoking the session of the current token.
+#[delete("/session")]
+pub async fn logout(app: web::Data<AppState>, token: Option<JwtToken>) -> Result<HttpResponse> {
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
+
+    // Tokens issued before sessions were introduced can not be revoked one by one.
+    if let Ok(session) = uuid::Uuid::parse_str(&token.jti) {
+        app.sessions.revoke(&app.pool, session).await?;
+    }
+    Ok(HttpResponse::Ok().json(&ApiResponse::empty()))
+}
+
+/// Revoke all sessions of the user, so that the user has to log in again on every device.
+#[delete("/user/{uid}/session")]
+pub async fn revoke_user_sessions(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    uid: web::Path<i32>,
+) -> Result<HttpResponse> {
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
+    let uid = uid.into_inner();
+    token.require(Permission::ManageUser)?;
+
+    let mut tx = app.pool.begin().await?;
+    audit::record(
+        &mut tx,
+        token.uid,
+        "user.session.revoke",
+        &format!("user:{}", uid),
+        None,
+    )
+    .await?;
+    app.sessions.revoke_all(tx, uid).await?;
+    Ok(HttpResponse::Ok().json(&ApiResponse::empty()))
+}
+
+/// Role managers may only give or take back roles whose permissions they hold themselves, and not on their
+/// own account, so that they cannot escalate. The admin role is not managed here at all.
+async fn check_role_change(app: &AppState, token: &JwtToken, uid: i32, role: &str) -> Result<()> {
+    token.require(Permission::ManageRole)?;
+
+    let is_admin = token.has_role(ADMIN_ROLE);
+    if role == ADMIN_ROLE || (uid == token.uid && !is_admin) {
+        return Err(ApiError::new(CommonError::Forbidden));
+    }
+    let permissions = role_permissions(&app.pool, role).await?;
+    if !is_admin && !permissions.iter().all(|p| token.permissions.contains(p)) {
+        return Err(ApiError::new(CommonError::Forbidden));
+    }
+    Ok(())
+}
+
+/// Give the role to the user. It takes effect after the user logs in or refreshes the token.
+#[put("/user/{uid}/role/{role}")]
+pub async fn add_user_role(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    path: web::Path<(i32, String)>,
+) -> Result<HttpResponse> {
+    let (uid, role) = path.into_inner();
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
+    check_role_change(&app, &token, uid, &role).await?;
+
+    Person::get(&app.pool, uid).await?;
+    let mut tx = app.pool.begin().await?;
+    add_role(&mut tx, uid, &role).await?;
+    let diff = serde_json::json!({ "role": role });
+    audit::record(
+        &mut tx,
+        token.uid,
+        "user.role.add",
+        &format!("user:{}", uid),
+        Some(diff),
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(HttpResponse::Ok().json(ApiResponse::empty()))
+}
+
+/// Take the role back from the user. Tokens issued before still carry it until they expire, so revoke the
+/// sessions of the user too if it must take effect at once.
+#[delete("/user/{uid}/role/{role}")]
+pub async fn remove_user_role(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    path: web::Path<(i32, String)>,
+) -> Result<HttpResponse> {
+    let (uid, role) = path.into_inner();
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
+    check_role_change(&app, &token, uid, &role).await?;
+
+    let mut tx = app.pool.begin().await?;
+    remove_role(&mut tx, uid, &role).await?;
+    let diff = serde_json::json!({ "role": role });
+    audit::record(
+        &mut tx,
+        token.uid,
+        "user.role.remove",
+        &format!("user:{}", uid),
+        Some(diff),
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(HttpResponse::Ok().json(ApiResponse::empty()))
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ListUsers {
@@ -76,7 +214,14 @@ pub struct ListUsers {
 }
 
 #[get("/user")]
-pub async fn list_users(app: web::Data<AppState>, form: web::Query<ListUsers>) -> Result<HttpResponse> {
+pub async fn list_users(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    form: web::Query<ListUsers>,
+) -> Result<HttpResponse> {
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
+    token.require(Permission::ManageUser)?;
+
     let parameter = form.into_inner();
     let userlist = Person::list(
         &app.pool,
@@ -84,6 +229,7 @@ pub async fn list_users(app: web::Data<AppState>, form: web::Query<ListUsers>) -
         parameter.page_size.unwrap_or(20),
     )
     .await?;
+    audit::record(&app.pool, token.uid, "user.list", "user", None).await?;
 
     Ok(HttpResponse::Ok().json(&ApiResponse::normal(userlist)))
 }
@@ -132,13 +278,12 @@ pub async fn create_user(
         .url
         .unwrap_or_else(|| get_default_avatar().to_string());
     user.register(&app.pool).await?;
+    let session = app.sessions.create(&app.pool, user.uid).await?;
 
     let response = serde_json::json!({
         "uid": user.uid,
-        "token": encode_jwt(&JwtToken {
-            uid: user.uid,
-            is_admin: user.is_admin,
-        })?
+        "token": encode_jwt(&JwtToken::new(&user, session, Grants::default()))?,
+        "refreshToken": RefreshToken::issue(&app.pool, user.uid, session).await?,
     });
 
     Ok(HttpResponse::Ok().json(ApiResponse::normal(response)))
@@ -151,13 +296,12 @@ pub async fn update_user_detail(
     form: web::Form<SubmittedPerson>,
     uid: web::Path<i32>,
 ) -> Result<HttpResponse> {
-    let token = token.unwrap();
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
     let uid = uid.into_inner();
 
-    if !token.is_admin && uid != token.uid {
-        return Err(ApiError::new(CommonError::Forbidden));
-    }
+    token.require_self_or(uid, Permission::ManageUser)?;
     let mut person = Person::get(&app.pool, uid).await?;
+    let before = serde_json::to_value(&person)?;
     let form = form.into_inner();
 
     if let Some(nick_name) = form.nick_name {
@@ -190,8 +334,20 @@ pub async fn update_user_detail(
         };
         person.avatar = final_url.unwrap_or_else(|| get_default_avatar().to_string());
     }
-    person.update(&app.pool).await?;
-
+    let mut tx = app.pool.begin().await?;
+    person.update(&mut tx).await?;
+    if token.uid != uid {
+        let diff = audit::diff(&before, &serde_json::to_value(&person)?);
+        audit::record(
+            &mut tx,
+            token.uid,
+            "user.update",
+            &format!("user:{}", uid),
+            Some(diff),
+        )
+        .await?;
+    }
+    tx.commit().await?;
     Ok(HttpResponse::Ok().json(ApiResponse::normal(person)))
 }
 
@@ -203,23 +359,22 @@ pub async fn bind_authentication(
     uid: web::Path<i32>,
 ) -> Result<HttpResponse> {
     let parameters: AuthParameters = form.into_inner();
-    let token = token.unwrap();
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
     let uid = uid.into_inner();
 
-    if token.uid != uid && !token.is_admin {
+    if token.uid != uid && !token.has_permission(Permission::ManageUser) {
         return Err(ApiError::new(CommonError::Parameter));
     }
     let user = Person::get(&app.pool, uid).await?;
 
-    match parameters {
+    let auth = match parameters {
         AuthParameters {
             login_type: LOGIN_BY_WECHAT,
             wechat_code: Some(wechat_code),
             ..
         } => {
             let wechat_token: WxSession = app.wx_client.code2session(&wechat_code).await?;
-            let auth: Authentication = Authentication::from_wechat(&wechat_token.openid);
-            user.update_authentication(&app.pool, &auth).await?;
+            Authentication::from_wechat(&wechat_token.openid)
         }
         AuthParameters {
             login_type: LOGIN_BY_PASSWORD,
@@ -229,35 +384,115 @@ pub async fn bind_authentication(
         } => {
             // Patch: Ordinary users are not allowed to log in with a password,
             // so as to prevent abuse of the interface.
-            if !token.is_admin {
+            if !token.has_permission(Permission::ManageUser) {
                 return Err(ApiError::new(UserError::AuthTypeNotAllowed));
             }
-            let auth = Authentication::from_password(username, password);
-            user.update_authentication(&app.pool, &auth).await?;
+            Authentication::from_password(username, password)
         }
         _ => {
             return Err(ApiError::new(CommonError::Parameter));
         }
+    };
+    let mut tx = app.pool.begin().await?;
+    user.update_authentication(&mut tx, &auth).await?;
+    // Credentials are never recorded.
+    if token.uid != uid || auth.login_type == LOGIN_BY_PASSWORD {
+        let diff = serde_json::json!({"loginType": auth.login_type, "account": auth.account});
+        let target = format!("user:{}", uid);
+        audit::record(
+            &mut tx,
+            token.uid,
+            "user.authentication.bind",
+            &target,
+            Some(diff),
+        )
+        .await?;
     }
+    tx.commit().await?;
     Ok(HttpResponse::Ok().json(ApiResponse::empty()))
 }
 
-#[get("/user/{uid}")]
-pub async fn get_user_detail(
+#[get("/user/{uid}/authentication")]
+pub async fn list_authentications(
     app: web::Data<AppState>,
     token: Option<JwtToken>,
     uid: web::Path<i32>,
 ) -> Result<HttpResponse> {
-    if token.is_none() {
-        return Err(ApiError::new(CommonError::Forbidden));
+    let uid = uid.into_inner();
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
+    token.require_self_or(uid, Permission::ManageUser)?;
+
+    let methods = Person::list_authentications(&app.pool, uid).await?;
+    if token.uid != uid {
+        let target = format!("user:{}", uid);
+        audit::record(&app.pool, token.uid, "user.authentication.list", &target, None).await?;
     }
-    let token = token.unwrap();
+    Ok(HttpResponse::Ok().json(&ApiResponse::normal(methods)))
+}
+
+/// Unbind a login method. The last one is kept, so that the user can still log in.
+#[delete("/user/{uid}/authentication/{login_type}")]
+pub async fn remove_authentication(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse> {
+    let (uid, login_type) = path.into_inner();
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
+    token.require_self_or(uid, Permission::ManageUser)?;
+
+    let user = Person::get(&app.pool, uid).await?;
+    let mut tx = app.pool.begin().await?;
+    user.remove_authentication(&mut tx, login_type).await?;
+    if token.uid != uid {
+        let diff = serde_json::json!({ "loginType": login_type });
+        let target = format!("user:{}", uid);
+        audit::record(
+            &mut tx,
+            token.uid,
+            "user.authentication.remove",
+            &target,
+            Some(diff),
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(HttpResponse::Ok().json(&ApiResponse::empty()))
+}
+
+#[get("/user/{uid}/authentication/history")]
+pub async fn get_authentication_history(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    uid: web::Path<i32>,
+    page: web::Query<PageView>,
+) -> Result<HttpResponse> {
     let uid = uid.into_inner();
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
+    token.require_self_or(uid, Permission::ManageUser)?;
 
-    if uid != token.uid && !token.is_admin {
-        return Err(ApiError::new(CommonError::Forbidden));
+    let logs = Person::authentication_history(&app.pool, uid, &page).await?;
+    if token.uid != uid {
+        let target = format!("user:{}", uid);
+        audit::record(&app.pool, token.uid, "user.authentication.history", &target, None).await?;
     }
+    Ok(HttpResponse::Ok().json(&ApiResponse::normal(logs)))
+}
+
+#[get("/user/{uid}")]
+pub async fn get_user_detail(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    uid: web::Path<i32>,
+) -> Result<HttpResponse> {
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
+    let uid = uid.into_inner();
+
+    token.require_self_or(uid, Permission::ManageUser)?;
     let user = Person::get(&app.pool, uid).await?;
+    if token.uid != uid {
+        audit::record(&app.pool, token.uid, "user.get", &format!("user:{}", uid), None).await?;
+    }
     Ok(HttpResponse::Ok().json(&ApiResponse::normal(&user)))
 }
 
@@ -268,16 +503,24 @@ pub async fn get_user_identity(
     uid: web::Path<i32>,
 ) -> Result<HttpResponse> {
     let uid = uid.into_inner();
-    let token = token.unwrap();
+    let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
 
-    if token.uid != uid && !token.is_admin {
-        return Err(ApiError::new(CommonError::Forbidden));
-    }
+    token.require_self_or(uid, Permission::ManageUser)?;
 
-    Person::get_identity(&app.pool, uid)
+    let identity = Person::get_identity(&app.pool, uid)
         .await?
-        .map(|i| HttpResponse::Ok().json(&ApiResponse::normal(i)))
-        .ok_or_else(|| ApiError::new(UserError::NoSuchUser))
+        .ok_or_else(|| ApiError::new(UserError::NoSuchUser))?;
+    if token.uid != uid {
+        audit::record(
+            &app.pool,
+            token.uid,
+            "user.identity.get",
+            &format!("user:{}", uid),
+            None,
+        )
+        .await?;
+    }
+    Ok(HttpResponse::Ok().json(&ApiResponse::normal(identity)))
 }
 
 #[derive(Deserialize)]
@@ -299,9 +542,7 @@ pub async fn set_user_identity(
     let uid = uid.into_inner();
     let token = token.ok_or_else(|| ApiError::new(CommonError::LoginNeeded))?;
 
-    if token.uid != uid && !token.is_admin {
-        return Err(ApiError::new(CommonError::Forbidden));
-    }
+    token.require_self_or(uid, Permission::ManageUser)?;
     let identity_post = data.into_inner();
     let mut identity = Identity {
         uid,
@@ -310,7 +551,153 @@ pub async fn set_user_identity(
         oa_certified: false,
     };
     let person = Person::get(&app.pool, uid).await?;
-    person.set_identity(&app.pool, &mut identity, &app.agents).await?;
+    let before = Person::get_identity(&app.pool, uid).await?;
+    identity.certify(&app.agents).await?;
 
+    let mut tx = app.pool.begin().await?;
+    person.set_identity(&mut tx, &identity).await?;
+    // The OA secret is never serialized, so it is not recorded.
+    if token.uid != uid {
+        let diff = audit::diff(&serde_json::to_value(&before)?, &serde_json::to_value(&identity)?);
+        let target = format!("user:{}", uid);
+        audit::record(&mut tx, token.uid, "user.identity.set", &target, Some(diff)).await?;
+    }
+    tx.commit().await?;
     Ok(HttpResponse::Ok().json(&ApiResponse::empty()))
 }
+
+#[cfg(test)]
+mod test {
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    use crate::bridge::fake::start_host;
+    use crate::models::audit::{self, AuditFilter};
+    use crate::models::user::{Grants, Permission, Person, UserError};
+    use crate::models::{CommonError, PageView};
+    use crate::services::fake::{app_state, bearer, bearer_with};
+
+    #[tokio::test]
+    pub async fn add_user_role_without_permission() {
+        let (agents, _) = start_host("").await;
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state(agents))
+                .service(super::add_user_role),
+        )
+        .await;
+
+        let request = test::TestRequest::put()
+            .uri("/user/10/role/admin")
+            .insert_header(("Authorization", bearer(10)))
+            .to_request();
+        let response: Value = test::read_response_json(&app, request).await;
+        assert_eq!(response["code"], CommonError::Forbidden as u16);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs KITE_TEST_DB"]
+    pub async fn update_user_detail() {
+        let (agents, _) = start_host("").await;
+        let app_state = app_state(agents);
+        let pool = app_state.pool.clone();
+        let mut person = Person::default();
+        person.register(&pool).await.unwrap();
+        let app =
+            test::init_service(App::new().app_data(app_state).service(super::update_user_detail)).await;
+
+        let request = test::TestRequest::put()
+            .uri(&format!("/user/{}", person.uid))
+            .insert_header(("Authorization", bearer(person.uid)))
+            .set_form(&[("nickName", "kite"), ("city", "Shanghai")])
+            .to_request();
+        let response: Value = test::read_response_json(&app, request).await;
+        assert_eq!(response["code"], 0);
+
+        let person = Person::get(&pool, person.uid).await.unwrap();
+        assert_eq!(person.nick_name, "kite");
+        assert_eq!(person.city.as_deref(), Some("Shanghai"));
+    }
+
+    #[tokio::test]
+    pub async fn role_manager_cannot_escalate() {
+        let (agents, _) = start_host("").await;
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state(agents))
+                .service(super::add_user_role),
+        )
+        .await;
+        let operator = bearer_with(10, &[Permission::ManageRole, Permission::ManageEvent]);
+
+        // Neither the admin role to anyone, nor any role to the operator itself.
+        for uri in ["/user/11/role/admin", "/user/10/role/event-organizer"].iter() {
+            let request = test::TestRequest::put()
+                .uri(uri)
+                .insert_header(("Authorization", operator.clone()))
+                .to_request();
+            let response: Value = test::read_response_json(&app, request).await;
+            assert_eq!(response["code"], CommonError::Forbidden as u16, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs KITE_TEST_DB"]
+    pub async fn add_and_remove_user_role() {
+        let (agents, _) = start_host("").await;
+        let app_state = app_state(agents);
+        let mut person = Person::default();
+        person.register(&app_state.pool).await.unwrap();
+        let pool = app_state.pool.clone();
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state)
+                .service(super::add_user_role)
+                .service(super::remove_user_role),
+        )
+        .await;
+        let operator = bearer_with(1, &[Permission::ManageRole, Permission::ManageEvent]);
+
+        let request = test::TestRequest::put()
+            .uri(&format!("/user/{}/role/no-such-role", person.uid))
+            .insert_header(("Authorization", operator.clone()))
+            .to_request();
+        let response: Value = test::read_response_json(&app, request).await;
+        assert_eq!(response["code"], UserError::NoSuchRole as u16);
+
+        // The operator does not hold freshman.manage, so it cannot give the role granting it.
+        let request = test::TestRequest::put()
+            .uri(&format!("/user/{}/role/freshman-operator", person.uid))
+            .insert_header(("Authorization", operator.clone()))
+            .to_request();
+        let response: Value = test::read_response_json(&app, request).await;
+        assert_eq!(response["code"], CommonError::Forbidden as u16);
+
+        let request = test::TestRequest::put()
+            .uri(&format!("/user/{}/role/event-organizer", person.uid))
+            .insert_header(("Authorization", operator.clone()))
+            .to_request();
+        let response: Value = test::read_response_json(&app, request).await;
+        assert_eq!(response["code"], 0);
+        let grants = Grants::of(&pool, person.uid).await.unwrap();
+        assert!(grants.permissions.contains(&"event.manage".to_string()));
+
+        let request = test::TestRequest::delete()
+            .uri(&format!("/user/{}/role/event-organizer", person.uid))
+            .insert_header(("Authorization", operator))
+            .to_request();
+        let response: Value = test::read_response_json(&app, request).await;
+        assert_eq!(response["code"], 0);
+        let grants = Grants::of(&pool, person.uid).await.unwrap();
+        assert!(grants.roles.is_empty());
+
+        // Both changes are recorded, and the refused one is not.
+        let filter = AuditFilter {
+            target: Some(format!("user:{}", person.uid)),
+            ..AuditFilter::default()
+        };
+        let records = audit::query(&pool, &filter, &PageView::default()).await.unwrap();
+        let actions: Vec<&str> = records.iter().map(|record| record.action.as_str()).collect();
+        assert_eq!(actions, ["user.role.remove", "user.role.add"]);
+    }
+}
@@ -1,6 +1,10 @@
 pub use acl::Auth;
+pub use rate_limit::{RateLimit, RateLimiter};
 pub use reject::Reject;
+pub use request_id::RequestIdentity;
 
 mod acl;
 mod logger;
+mod rate_limit;
 mod reject;
+mod request_id;
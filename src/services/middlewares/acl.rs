@@ -1,37 +1,143 @@
-use std::collections::HashMap;
+use std::rc::Rc;
 use std::result::Result;
+use std::str::FromStr;
 
 use actix_service::{Service, Transform};
-use actix_utils::future::{self, ok, Ready};
+use actix_utils::future::{self, Ready};
 use actix_web::{
-    dev::{ServiceRequest, ServiceResponse},
-    http::Method,
-    Error,
+    dev::{ResourceDef, ServiceRequest, ServiceResponse},
+    http::{header, Method},
+    web, Error,
 };
-use futures_util::future::Either;
+use futures_util::future::LocalBoxFuture;
 
+use crate::config::{AccessRuleConfig, CONFIG};
 use crate::error::ApiError;
 use crate::jwt::*;
 use crate::models::CommonError;
-use crate::services::{get_auth_bearer_value, JwtToken};
-
-const URL_WHITE_LIST: [(&str, Method); 9] = [
-    ("/api/v1/", Method::GET),
-    ("/api/v1/session", Method::POST),
-    ("/api/v1/user", Method::POST),
-    ("/api/v1/event", Method::GET),
-    ("/api/v1/motto", Method::GET),
-    ("/api/v1/notice", Method::GET),
-    ("/api/v1/edu/schedule", Method::GET),
-    ("/api/v1/edu/calendar", Method::GET),
-    ("/api/v1/edu/timetable/ics/content", Method::GET),
-];
+use crate::services::{get_auth_bearer_value, AppState, JwtToken};
+
+/// Who may access a route.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Access {
+    /// Anyone, without a token.
+    Public,
+    /// Logged-in users.
+    Authenticated,
+    /// Logged-in users having the role.
+    Role(String),
+}
+
+impl FromStr for Access {
+    type Err = String;
+
+    /// Parse "public", "authenticated" or "role:<name>".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "public" => Ok(Access::Public),
+            "authenticated" => Ok(Access::Authenticated),
+            _ => match s.strip_prefix("role:") {
+                Some(role) if !role.is_empty() => Ok(Access::Role(role.to_string())),
+                _ => Err(format!("Invalid access \"{}\"", s)),
+            },
+        }
+    }
+}
+
+/// Parse the method in config, where None or "*" means any method.
+pub(super) fn parse_method(method: Option<&str>) -> Result<Option<Method>, String> {
+    match method {
+        None | Some("*") => Ok(None),
+        Some(method) => Method::from_str(&method.to_uppercase())
+            .map(Some)
+            .map_err(|e| e.to_string()),
+    }
+}
+
+/// Access rule of routes matching the path pattern, like "/api/v1/mall/goods/{item_code}".
+pub struct AccessRule {
+    /// Any method if not set.
+    method: Option<Method>,
+    path: ResourceDef,
+    access: Access,
+}
+
+impl AccessRule {
+    pub fn new(method: Option<Method>, path: &str, access: Access) -> Self {
+        Self {
+            method,
+            path: ResourceDef::new(path),
+            access,
+        }
+    }
+
+    fn from_config(config: &AccessRuleConfig) -> Result<Self, String> {
+        let method = parse_method(config.method.as_deref())?;
+        Ok(Self::new(method, &config.path, config.access.parse()?))
+    }
+
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        self.method.as_ref().map_or(true, |m| m == method) && self.path.is_match(path)
+    }
+}
+
+/// Built-in rules. Routes not matched by any rule are accessible to logged-in users.
+fn default_rules() -> Vec<AccessRule> {
+    use Access::Public;
+
+    let get = || Some(Method::GET);
+    let post = || Some(Method::POST);
+    vec![
+        AccessRule::new(get(), "/api/v1/", Public),
+        AccessRule::new(post(), "/api/v1/session", Public),
+        AccessRule::new(post(), "/api/v1/session/refresh", Public),
+        AccessRule::new(post(), "/api/v1/user", Public),
+        AccessRule::new(get(), "/api/v1/event", Public),
+        AccessRule::new(get(), "/api/v1/motto", Public),
+        AccessRule::new(get(), "/api/v1/notice", Public),
+        AccessRule::new(get(), "/api/v1/edu/schedule", Public),
+        AccessRule::new(get(), "/api/v1/edu/calendar", Public),
+        AccessRule::new(get(), "/api/v1/edu/timetable/ics/content", Public),
+        AccessRule::new(get(), "/api/v1/mall/sort", Public),
+        AccessRule::new(get(), "/api/v1/mall/textbook/{isbn}", Public),
+        AccessRule::new(get(), "/api/v1/mall/goods", Public),
+        AccessRule::new(get(), "/api/v1/mall/goods/sort/{sort}", Public),
+        AccessRule::new(get(), "/api/v1/mall/goods/like/{keyword}", Public),
+        AccessRule::new(get(), "/api/v1/mall/goods/{item_code}", Public),
+        // Agents are authenticated by the handshake on the connection.
+        AccessRule::new(get(), "/api/v1/agent/ws", Public),
+    ]
+}
+
+/// Rules from `server.access` config, matched before the built-in ones.
+fn load_rules() -> Vec<AccessRule> {
+    let mut rules: Vec<AccessRule> = CONFIG
+        .server
+        .access
+        .iter()
+        .map(|config| {
+            AccessRule::from_config(config)
+                .unwrap_or_else(|e| panic!("Invalid access rule for {}: {}", config.path, e))
+        })
+        .collect();
+
+    rules.extend(default_rules());
+    rules
+}
+
+fn find_access(rules: &[AccessRule], method: &Method, path: &str) -> Access {
+    rules
+        .iter()
+        .find(|rule| rule.matches(method, path))
+        .map(|rule| rule.access.clone())
+        .unwrap_or(Access::Authenticated)
+}
 
 pub struct Auth;
 
 impl<S> Transform<S, ServiceRequest> for Auth
 where
-    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
     S::Future: 'static,
 {
     type Response = ServiceResponse;
@@ -41,53 +147,171 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        let mut no_checking_urls = HashMap::new();
-
-        for (path, method) in URL_WHITE_LIST.iter() {
-            no_checking_urls.insert(<&str>::clone(path), method.clone());
-        }
         future::ok(AuthMiddleware {
-            service,
-            no_checking_urls,
+            service: Rc::new(service),
+            rules: load_rules(),
         })
     }
 }
 
 pub struct AuthMiddleware<S> {
-    service: S,
-    no_checking_urls: HashMap<&'static str, Method>,
+    service: Rc<S>,
+    rules: Vec<AccessRule>,
 }
 
 impl<S> Service<ServiceRequest> for AuthMiddleware<S>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
     S::Future: 'static,
 {
     type Response = ServiceResponse;
     type Error = Error;
-    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     actix_service::forward_ready!(service);
 
-    fn call(&self, req: ServiceRequest) -> Self::Future {
-        let url = req.path();
-        if let Some(method) = self.no_checking_urls.get(url) {
-            if method == req.method() {
-                return Either::Left(self.service.call(req));
-            }
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let access = find_access(&self.rules, req.method(), req.path());
+        let token = req
+            .headers()
+            .get("Authorization")
+            // If authentication type is "Bearer"
+            .and_then(get_auth_bearer_value)
+            // Unpack JWT to verify credential
+            .and_then(decode_jwt::<JwtToken>);
+        let app = req.app_data::<web::Data<AppState>>().cloned();
+
+        if access == Access::Public {
+            // Tokens are optional here, but handlers may still act on them. Those of revoked sessions and
+            // disabled accounts are dropped, so that the request is served as an anonymous one.
+            let (token, app) = match (token, app) {
+                (Some(token), Some(app)) => (token, app),
+                _ => return Box::pin(self.service.call(req)),
+            };
+            let service = self.service.clone();
+            return Box::pin(async move {
+                match app
+                    .sessions
+                    .is_active(&app.pool, token.uid, &token.jti, token.iat)
+                    .await
+                {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        req.headers_mut().remove(header::AUTHORIZATION);
+                    }
+                    Err(e) => return Ok(req.error_response(e)),
+                }
+                service.call(req).await
+            });
         }
 
         // For logined users, they can access all of the resources, and then each module will check
         // whether they can do or not.
-        if let Some(auth_string) = req.headers().get("Authorization") {
-            // If authentication type is "Bearer"
-            if let Some(jwt_string) = get_auth_bearer_value(auth_string) {
-                // Unpack JWT to verify credential
-                if validate_jwt::<JwtToken>(jwt_string) {
-                    return Either::Left(self.service.call(req));
-                }
+        let (token, app) = match (token, app) {
+            (Some(token), Some(app)) => (token, app),
+            _ => {
+                let response = req.error_response(ApiError::new(CommonError::LoginNeeded));
+                return Box::pin(async move { Ok(response) });
+            }
+        };
+        if let Access::Role(role) = &access {
+            if !token.has_role(role) {
+                let response = req.error_response(ApiError::new(CommonError::Forbidden));
+                return Box::pin(async move { Ok(response) });
             }
         }
-        Either::Right(ok(req.error_response(ApiError::new(CommonError::LoginNeeded))))
+        // Tokens of revoked sessions and disabled accounts are rejected.
+        let service = self.service.clone();
+        Box::pin(async move {
+            match app
+                .sessions
+                .is_active(&app.pool, token.uid, &token.jti, token.iat)
+                .await
+            {
+                Ok(true) => service.call(req).await,
+                Ok(false) => Ok(req.error_response(ApiError::new(CommonError::LoginNeeded))),
+                Err(e) => Ok(req.error_response(e)),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{test, App};
+
+    use super::*;
+    use crate::bridge::fake::start_host;
+    use crate::models::user::Person;
+    use crate::services::fake::app_state;
+
+    #[test]
+    pub fn match_access_rules() {
+        let mut rules = vec![AccessRule::new(
+            Some(Method::DELETE),
+            "/api/v1/mall/goods/{pub_code}",
+            "role:mall-moderator".parse().unwrap(),
+        )];
+        rules.extend(default_rules());
+
+        let access = |method: Method, path: &str| find_access(&rules, &method, path);
+        assert_eq!(access(Method::GET, "/api/v1/mall/goods/G2021"), Access::Public);
+        assert_eq!(
+            access(Method::DELETE, "/api/v1/mall/goods/P2021"),
+            Access::Role("mall-moderator".to_string())
+        );
+        assert_eq!(access(Method::POST, "/api/v1/event"), Access::Authenticated);
+        assert_eq!(
+            access(Method::GET, "/api/v1/mall/goods/G2021/x"),
+            Access::Authenticated
+        );
+        assert!("role:".parse::<Access>().is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs KITE_TEST_DB"]
+    pub async fn drop_revoked_token_on_public_route() {
+        let (agents, _) = start_host("").await;
+        let app_state = app_state(agents);
+        let mut person = Person::default();
+        person.register(&app_state.pool).await.unwrap();
+        let session = app_state
+            .sessions
+            .create(&app_state.pool, person.uid)
+            .await
+            .unwrap();
+
+        let app = test::init_service(App::new().wrap(Auth).app_data(app_state.clone()).route(
+            "/api/v1/motto",
+            web::get().to(|token: Option<JwtToken>| async move { token.is_some().to_string() }),
+        ))
+        .await;
+        let token = JwtToken {
+            uid: person.uid,
+            is_admin: false,
+            exp: chrono::Utc::now().timestamp() + 600,
+            iat: chrono::Utc::now().timestamp(),
+            jti: session.to_string(),
+            roles: Vec::new(),
+            permissions: Vec::new(),
+        };
+        let bearer = format!("Bearer {}", encode_jwt(&token).unwrap());
+        let request = || {
+            test::TestRequest::get()
+                .uri("/api/v1/motto")
+                .insert_header(("Authorization", bearer.clone()))
+                .to_request()
+        };
+        assert_eq!(
+            test::read_body(test::call_service(&app, request()).await).await,
+            "true"
+        );
+
+        // The handler sees no token after the session is revoked.
+        app_state.sessions.revoke(&app_state.pool, session).await.unwrap();
+        assert_eq!(
+            test::read_body(test::call_service(&app, request()).await).await,
+            "false"
+        );
     }
 }
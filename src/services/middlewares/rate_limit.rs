@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ResourceDef, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, HeaderValue, Method};
+use actix_web::Error;
+use futures::future::{ok, Either, Ready};
+
+use crate::config::RateLimitConfig;
+use crate::error::ApiError;
+use crate::jwt::decode_jwt;
+use crate::models::CommonError;
+use crate::services::{get_auth_bearer_value, JwtToken};
+
+use super::acl::parse_method;
+
+/// Max buckets kept. Buckets idle for long are dropped first, since they are most likely full again.
+const MAX_BUCKETS: usize = 100000;
+
+/// Routes sharing a limit, like the login endpoint or all library queries.
+struct RateGroup {
+    /// Any method if not set.
+    method: Option<Method>,
+    path: ResourceDef,
+    /// Max requests in a burst.
+    capacity: f64,
+    /// Requests allowed per second in the long run.
+    rate: f64,
+}
+
+impl RateGroup {
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        self.method.as_ref().map_or(true, |m| m == method) && self.path.is_match(path)
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    time: Instant,
+}
+
+impl Bucket {
+    fn new(capacity: f64, now: Instant) -> Self {
+        Self {
+            tokens: capacity,
+            time: now,
+        }
+    }
+
+    fn refill(&mut self, group: &RateGroup, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.time).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * group.rate).min(group.capacity);
+        self.time = now;
+    }
+
+    /// Take a token, or return how long to wait for one.
+    fn take(&mut self, group: &RateGroup, now: Instant) -> Result<(), Duration> {
+        self.refill(group, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / group.rate))
+        }
+    }
+}
+
+type BucketMap = HashMap<(usize, String), Bucket>;
+
+/// Buckets in two generations, as an approximate LRU with constant time eviction. Buckets used are moved to the
+/// current generation, and when it is half of the capacity, the previous generation is dropped as a whole.
+struct Buckets {
+    capacity: usize,
+    current: BucketMap,
+    previous: BucketMap,
+}
+
+impl Buckets {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            current: HashMap::new(),
+            previous: HashMap::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.current.len() + self.previous.len()
+    }
+
+    /// Take a token from the bucket of the group and key. The generation retired, if any, is returned to be
+    /// dropped after the lock is released.
+    fn take(
+        &mut self,
+        index: usize,
+        key: String,
+        group: &RateGroup,
+        now: Instant,
+    ) -> (Result<(), Duration>, Option<BucketMap>) {
+        let key = (index, key);
+        let mut retired = None;
+
+        if !self.current.contains_key(&key) && self.current.len() >= self.capacity / 2 {
+            let current = mem::take(&mut self.current);
+            retired = Some(mem::replace(&mut self.previous, current));
+        }
+        let previous = &mut self.previous;
+        let bucket = self.current.entry(key).or_insert_with_key(|key| {
+            previous
+                .remove(key)
+                .unwrap_or_else(|| Bucket::new(group.capacity, now))
+        });
+        (bucket.take(group, now), retired)
+    }
+}
+
+/// Token buckets of each group, by uid for logged-in users and by client IP for others.
+pub struct RateLimiter {
+    groups: Vec<RateGroup>,
+    buckets: Mutex<Buckets>,
+    trusted_proxies: usize,
+}
+
+impl RateLimiter {
+    pub fn new(configs: &[RateLimitConfig], trusted_proxies: usize) -> Self {
+        let groups = configs
+            .iter()
+            .map(|config| {
+                let method = parse_method(config.method.as_deref())
+                    .unwrap_or_else(|e| panic!("Invalid rate limit for {}: {}", config.path, e));
+                if config.capacity < 1.0 || config.rate <= 0.0 {
+                    panic!(
+                        "Invalid rate limit for {}: capacity and rate are too small",
+                        config.path
+                    );
+                }
+                RateGroup {
+                    method,
+                    path: ResourceDef::new(config.path.as_str()),
+                    capacity: config.capacity,
+                    rate: config.rate,
+                }
+            })
+            .collect();
+
+        Self {
+            groups,
+            buckets: Mutex::new(Buckets::new(MAX_BUCKETS)),
+            trusted_proxies,
+        }
+    }
+
+    /// Count the request in the first matching group. Return how long to wait if it is rejected.
+    fn check(&self, method: &Method, path: &str, key: String, now: Instant) -> Result<(), Duration> {
+        let index = match self.groups.iter().position(|group| group.matches(method, path)) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let group = &self.groups[index];
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let (result, retired) = buckets.take(index, key, group, now);
+        drop(buckets);
+        drop(retired);
+        result
+    }
+
+    /// Key of the client: uid in the token, or the client IP.
+    ///
+    /// Each trusted proxy appends the address it got the request from to "X-Forwarded-For", so the client IP is
+    /// the entry as many as the proxies from the right. Entries on the left of it are sent by the client, and
+    /// can not be trusted. The peer address is used if there is no proxy or the header is too short.
+    fn client_key(&self, req: &ServiceRequest) -> String {
+        let token = req
+            .headers()
+            .get("Authorization")
+            .and_then(get_auth_bearer_value)
+            .and_then(decode_jwt::<JwtToken>);
+        if let Some(token) = token {
+            return format!("uid:{}", token.uid);
+        }
+
+        let forwarded: Vec<&str> = req
+            .headers()
+            .get_all("X-Forwarded-For")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        let addr = match forwarded.len().checked_sub(self.trusted_proxies) {
+            Some(index) if self.trusted_proxies > 0 => forwarded[index].trim().to_string(),
+            _ => req
+                .peer_addr()
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_default(),
+        };
+        format!("ip:{}", addr)
+    }
+}
+
+pub struct RateLimit {
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimit {
+    pub fn new(limiter: Arc<RateLimiter>) -> Self {
+        Self { limiter }
+    }
+}
+
+impl<S> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            service,
+            limiter: self.limiter.clone(),
+        })
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: S,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let key = self.limiter.client_key(&req);
+        let wait = match self.limiter.check(req.method(), req.path(), key, Instant::now()) {
+            Ok(()) => return Either::Left(self.service.call(req)),
+            Err(wait) => wait,
+        };
+        // Round up, so that the client does not retry too early.
+        let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+
+        let mut error = ApiError::new(CommonError::TooManyRequests);
+        error.error_msg = Some(format!("请求过于频繁, 请在 {} 秒后重试", seconds));
+        let mut response = req.error_response(error);
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+        Either::Right(ok(response))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::test;
+
+    use super::*;
+
+    #[test]
+    pub fn token_bucket() {
+        let config = RateLimitConfig {
+            path: "/api/v1/session".to_string(),
+            method: Some("POST".to_string()),
+            capacity: 2.0,
+            rate: 0.5,
+        };
+        let limiter = RateLimiter::new(&[config], 0);
+        let start = Instant::now();
+        let check = |method: Method, key: &str, secs: u64| {
+            limiter.check(
+                &method,
+                "/api/v1/session",
+                key.to_string(),
+                start + Duration::from_secs(secs),
+            )
+        };
+
+        assert!(check(Method::POST, "ip:1", 0).is_ok());
+        assert!(check(Method::POST, "ip:1", 0).is_ok());
+        assert_eq!(check(Method::POST, "ip:1", 0), Err(Duration::from_secs(2)));
+        // Other clients and routes are not affected.
+        assert!(check(Method::POST, "ip:2", 0).is_ok());
+        assert!(check(Method::GET, "ip:1", 0).is_ok());
+        // One token is refilled every two seconds.
+        assert!(check(Method::POST, "ip:1", 2).is_ok());
+        assert!(check(Method::POST, "ip:1", 2).is_err());
+    }
+
+    #[test]
+    pub fn evict_idle_buckets() {
+        let group = RateGroup {
+            method: None,
+            path: ResourceDef::new("/"),
+            capacity: 1.0,
+            rate: 0.001,
+        };
+        let mut buckets = Buckets::new(4);
+        let now = Instant::now();
+        let mut take = |key: &str| {
+            let (result, _) = buckets.take(0, key.to_string(), &group, now);
+            result
+        };
+
+        assert!(take("ip:1").is_ok());
+        for key in ["ip:2", "ip:3", "ip:4", "ip:5"] {
+            assert!(take(key).is_ok());
+            // The bucket of "ip:1" is kept while used.
+            assert!(take("ip:1").is_err());
+        }
+        // Idle ones are dropped, and the total is bounded.
+        assert!(take("ip:2").is_ok());
+        assert!(buckets.len() <= 4);
+    }
+
+    #[test]
+    pub fn client_key() {
+        let addr = "10.0.0.1:40000".parse().unwrap();
+        let request = || {
+            test::TestRequest::default()
+                .peer_addr(addr)
+                .insert_header(("X-Forwarded-For", "1.1.1.1, 2.2.2.2"))
+                .append_header(("X-Forwarded-For", "3.3.3.3"))
+                .to_srv_request()
+        };
+
+        // The header is ignored without trusted proxies.
+        assert_eq!(RateLimiter::new(&[], 0).client_key(&request()), "ip:10.0.0.1");
+        // The rightmost entries are added by the proxies, and the ones on the left may be spoofed.
+        assert_eq!(RateLimiter::new(&[], 1).client_key(&request()), "ip:3.3.3.3");
+        assert_eq!(RateLimiter::new(&[], 2).client_key(&request()), "ip:2.2.2.2");
+        assert_eq!(RateLimiter::new(&[], 4).client_key(&request()), "ip:10.0.0.1");
+    }
+}
@@ -0,0 +1,73 @@
+use actix_service::{Service, Transform};
+use actix_utils::future::{ok, Ready};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{HeaderName, HeaderValue};
+use actix_web::{Error, HttpMessage};
+use futures_util::future::LocalBoxFuture;
+
+use crate::request_id;
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Request ID stored in request extensions.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// Accept the request ID from client or generate one, and echo it in the `X-Request-Id` response header.
+pub struct RequestIdentity;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestIdentity
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestIdentityMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestIdentityMiddleware { service })
+    }
+}
+
+pub struct RequestIdentityMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdentityMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| request_id::is_valid(id))
+            .map(ToString::to_string)
+            .unwrap_or_else(request_id::generate);
+        req.extensions_mut().insert(RequestId(id.clone()));
+
+        // Inner middlewares like Auth may respond immediately, so the ID is also set while calling them.
+        let future = request_id::sync_scope(id.clone(), || self.service.call(req));
+        Box::pin(async move {
+            let mut response = request_id::scope(id.clone(), future).await?;
+
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                response
+                    .headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+            Ok(response)
+        })
+    }
+}